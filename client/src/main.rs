@@ -1,23 +1,28 @@
 mod state;
+mod tls;
 
 use state::State;
 
 use shared::{
-    message::{GameAction, MessageFromClient, MessageFromServer},
+    frame::FrameDecoder,
+    message::{
+        GameAction, MessageFromClient, MessageFromServer, RoomFilter, RoomOptions, RoomSummary,
+    },
+    rating::PlayerStats,
+    replay::ReplaySummary,
     serialize::{deserialize, serialize},
 };
 use std::{
     net::IpAddr,
+    path::PathBuf,
     sync::{Arc, Mutex},
 };
 use structopt::StructOpt;
 use tokio::{
-    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
 };
+use tokio_rustls::rustls::pki_types::ServerName;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "tic tac toe client", about = "A tic tac toe client, probably")]
@@ -27,6 +32,16 @@ struct Opt {
 
     #[structopt(default_value = "1337")]
     port: u16,
+
+    /// Connect over TLS, pass the port of the `tls_address` of the server
+    /// with it, TLS is off on the server unless that address is set
+    #[structopt(long)]
+    tls: bool,
+
+    /// PEM file with the certificate to trust instead of the usual web
+    /// roots, e.g. the self-signed certificate of the server
+    #[structopt(long, parse(from_os_str), requires = "tls")]
+    ca_cert: Option<PathBuf>,
 }
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
@@ -35,72 +50,141 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync
 async fn main() -> Result<()> {
     let opt = Opt::from_args();
 
-    let (tcp_read, tcp_write) = {
-        let stream = TcpStream::connect((opt.ip_addr, opt.port)).await.expect(
-            &format!(
-                "cannot establish TCP connection with {}:{}",
-                opt.ip_addr, opt.port
-            )[..],
-        );
+    let stream = TcpStream::connect((opt.ip_addr, opt.port)).await.expect(
+        &format!(
+            "cannot establish TCP connection with {}:{}",
+            opt.ip_addr, opt.port
+        )[..],
+    );
+
+    if opt.tls {
+        let connector = tls::connector(opt.ca_cert.as_deref())?;
+        let stream = connector
+            .connect(ServerName::from(opt.ip_addr), stream)
+            .await
+            .map_err(|err| format!("TLS handshake with {} failed: {}", opt.ip_addr, err))?;
+        run(stream).await
+    } else {
+        run(stream).await
+    }
+}
 
-        stream.into_split()
-    };
+async fn run<S: AsyncRead + AsyncWrite>(stream: S) -> Result<()> {
+    let (tcp_read, tcp_write) = io::split(stream);
 
     let state = Arc::new(Mutex::new(State::new()));
 
     tokio::select!(
         _ = receive_tcp(tcp_read, Arc::clone(&state)) => {
             println!("TCP connection closed (probably because of timeout)");
-            return Ok(());
         },
         _ = send_tcp(tcp_write, state) => {
             println!("Connection closed");
-            return Ok(());
         },
     );
+    Ok(())
 }
 
-async fn receive_tcp(tcp_read: OwnedReadHalf, state: Arc<Mutex<State>>) -> Result<()> {
-    let mut buf = Vec::<u8>::new();
+async fn receive_tcp<R: AsyncRead + Unpin>(
+    mut tcp_read: R,
+    state: Arc<Mutex<State>>,
+) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    let mut decoder = FrameDecoder::new();
 
-    let mut reader = BufReader::new(tcp_read);
-    while let Ok(size) = reader.read_until('\n' as u8, &mut buf).await {
+    while let Ok(size) = tcp_read.read(&mut buf).await {
         if size == 0 {
             break;
         }
-        let msg = deserialize::<MessageFromServer>(&buf)
-            .expect("Failed to deserialize message from server");
-        match msg {
-            MessageFromServer::State(game_state) => {
-                let mut state = state.lock().unwrap();
-                state.game_state = game_state;
-                state.print_game_state();
-            }
-            MessageFromServer::StateChanged(serialized_state_delta) => {
-                let mut state = state.lock().unwrap();
-                state.apply_patch(serialized_state_delta);
-                state.print_game_state();
+        decoder.extend(&buf[..size]);
+        while let Some(frame) = decoder.next_frame()? {
+            handle_message(&frame, &state);
+        }
+    }
+    Ok(())
+}
+
+fn handle_message(frame: &[u8], state: &Arc<Mutex<State>>) {
+    let msg =
+        deserialize::<MessageFromServer>(frame).expect("Failed to deserialize message from server");
+    match msg {
+        MessageFromServer::State(serialized_state) => {
+            let mut state = state.lock().unwrap();
+            state.set_game_state(serialized_state);
+            state.print_game_state();
+        }
+        MessageFromServer::StateChanged(serialized_state_delta) => {
+            let mut state = state.lock().unwrap();
+            state.apply_patch(serialized_state_delta);
+            state.print_game_state();
+        }
+        MessageFromServer::RoomFull
+        | MessageFromServer::RoomDoesNotExist
+        | MessageFromServer::RoomNameAlreadyTaken
+        | MessageFromServer::RoomKindDoesNotExist
+        | MessageFromServer::RoomLimitReached => {
+            state.lock().unwrap().room = None;
+            println!("{:?}", msg);
+        }
+        MessageFromServer::ServerJoined(token) => {
+            println!("Joined server, use session token {} to resume", token);
+        }
+        MessageFromServer::SessionResumed { username, room } => {
+            println!("Resumed session of {}", username);
+            let mut state = state.lock().unwrap();
+            state.username = Some(username);
+            state.room = room;
+        }
+        MessageFromServer::UsernameAlreadyTaken
+        | MessageFromServer::InvalidCredentials
+        | MessageFromServer::ServerFull => {
+            state.lock().unwrap().username = None;
+            println!("{:?}", msg);
+        }
+        MessageFromServer::RoomJoined(room_name) => {
+            println!("Joined room {}", room_name);
+            state.lock().unwrap().room = Some(room_name);
+        }
+        MessageFromServer::RoomList(rooms) => {
+            if rooms.is_empty() {
+                println!("No rooms found");
             }
-            MessageFromServer::RoomFull
-            | MessageFromServer::RoomDoesNotExist
-            | MessageFromServer::RoomNameAlreadyTaken => {
-                state.lock().unwrap().room = None;
-                println!("{:?}", msg);
+            rooms.iter().for_each(print_room_summary);
+        }
+        MessageFromServer::Leaderboard(players) => {
+            if players.is_empty() {
+                println!("No rated games yet");
             }
-            MessageFromServer::UsernameAlreadyTaken => {
-                state.lock().unwrap().username = None;
-                println!("{:?}", msg);
+            for (rank, stats) in players.iter().enumerate() {
+                print!("{}. ", rank + 1);
+                print_player_stats(stats);
             }
-            _ => {
-                println!("{:?}", msg);
+        }
+        MessageFromServer::PlayerStats(stats) => print_player_stats(&stats),
+        MessageFromServer::Replay(replay) => {
+            let mut state = state.lock().unwrap();
+            state.replay = Some((replay, 0));
+            state.print_replay();
+        }
+        MessageFromServer::ReplayList(replays) => {
+            if replays.is_empty() {
+                println!("No replays found");
             }
+            replays.iter().for_each(print_replay_summary);
+        }
+        MessageFromServer::ActionRejected(rejection) => {
+            println!("{}", rejection);
+        }
+        MessageFromServer::Error { code, message } => {
+            println!("Error {}: {}", u16::from(code), message);
+        }
+        _ => {
+            println!("{:?}", msg);
         }
-        buf.clear();
     }
-    Ok(())
 }
 
-async fn send_tcp(mut tcp_write: OwnedWriteHalf, state: Arc<Mutex<State>>) -> Result<()> {
+async fn send_tcp<W: AsyncWrite + Unpin>(mut tcp_write: W, state: Arc<Mutex<State>>) -> Result<()> {
     while let Ok(response) = prompt(None).await {
         let msg = match response.as_ref() {
             "join server" => {
@@ -109,12 +193,38 @@ async fn send_tcp(mut tcp_write: OwnedWriteHalf, state: Arc<Mutex<State>>) -> Re
                 state.username = Some(username.clone());
                 Some(MessageFromClient::JoinServer(username))
             }
+            "register" | "login" => {
+                let username = prompt(Some("username: ")).await?;
+                let password = prompt(Some("password: ")).await?;
+                state.lock().unwrap().username = Some(username.clone());
+                if response == "register" {
+                    Some(MessageFromClient::Register { username, password })
+                } else {
+                    Some(MessageFromClient::Login { username, password })
+                }
+            }
             "leave server" => Some(MessageFromClient::LeaveServer),
+            "resume" => {
+                let token = prompt(Some("session token: ")).await?;
+                match token.parse() {
+                    Ok(token) => Some(MessageFromClient::Resume(token)),
+                    Err(_) => {
+                        println!("Invalid session token");
+                        None
+                    }
+                }
+            }
             "create room" => {
                 let room_name = prompt(Some("room name: ")).await?;
+                let kind = prompt(Some("room kind (optional, default of the server): ")).await?;
+                let options = prompt(Some("room options (e.g. key=value, optional): ")).await?;
                 let mut state = state.lock().unwrap();
                 state.room = Some(room_name.clone());
-                Some(MessageFromClient::CreateRoom(room_name))
+                Some(MessageFromClient::CreateRoom {
+                    name: room_name,
+                    kind,
+                    options: parse_room_options(&options),
+                })
             }
             "join room" => {
                 let room_name = prompt(Some("room name: ")).await?;
@@ -122,6 +232,72 @@ async fn send_tcp(mut tcp_write: OwnedWriteHalf, state: Arc<Mutex<State>>) -> Re
                 state.room = Some(room_name.clone());
                 Some(MessageFromClient::JoinRoom(room_name))
             }
+            "spectate room" => {
+                let room_name = prompt(Some("room name: ")).await?;
+                let mut state = state.lock().unwrap();
+                state.room = Some(room_name.clone());
+                Some(MessageFromClient::SpectateRoom(room_name))
+            }
+            "list rooms" => {
+                let kind = prompt(Some("room kind (optional): ")).await?;
+                let open_only = prompt(Some("only rooms with open seats? (y/N): ")).await?;
+                Some(MessageFromClient::ListRooms(RoomFilter {
+                    open_only: open_only.eq_ignore_ascii_case("y"),
+                    kind: if kind.is_empty() { None } else { Some(kind) },
+                }))
+            }
+            "find match" => {
+                let kind = prompt(Some("room kind (optional, default of the server): ")).await?;
+                Some(MessageFromClient::FindMatch(kind))
+            }
+            "cancel match" => Some(MessageFromClient::CancelMatch),
+            "leaderboard" => {
+                let top_n = prompt(Some("number of players (default 10): ")).await?;
+                match top_n.parse() {
+                    Ok(top_n) => Some(MessageFromClient::GetLeaderboard { top_n }),
+                    Err(_) if top_n.is_empty() => {
+                        Some(MessageFromClient::GetLeaderboard { top_n: 10 })
+                    }
+                    Err(_) => {
+                        println!("Invalid number of players");
+                        None
+                    }
+                }
+            }
+            "replays" => {
+                let username = prompt(Some("username (default yourself): ")).await?;
+                let username = if username.is_empty() {
+                    state.lock().unwrap().username.clone()
+                } else {
+                    Some(username)
+                };
+                match username {
+                    Some(username) => Some(MessageFromClient::ListReplays(username)),
+                    None => {
+                        println!("Join the server first");
+                        None
+                    }
+                }
+            }
+            "next" => {
+                let mut state = state.lock().unwrap();
+                match &mut state.replay {
+                    Some((replay, moves)) if *moves < replay.moves.len() => {
+                        *moves += 1;
+                        state.print_replay();
+                    }
+                    Some(_) => {
+                        println!("End of replay");
+                        state.replay = None;
+                    }
+                    None => println!("No replay to step through, use \"replay <id>\" first"),
+                }
+                None
+            }
+            "stats" => {
+                let username = prompt(Some("username: ")).await?;
+                Some(MessageFromClient::GetPlayerStats(username))
+            }
             "leave room" => {
                 let mut state = state.lock().unwrap();
                 state.room = None;
@@ -133,7 +309,7 @@ async fn send_tcp(mut tcp_write: OwnedWriteHalf, state: Arc<Mutex<State>>) -> Re
                     println!("Still waiting for players!");
                     None
                 } else {
-                    Some(MessageFromClient::GameAction(GameAction::Restart))
+                    Some(MessageFromClient::game_action(&GameAction::Restart)?)
                 }
             }
             "" => None,
@@ -146,23 +322,22 @@ async fn send_tcp(mut tcp_write: OwnedWriteHalf, state: Arc<Mutex<State>>) -> Re
                     match positions[..] {
                         [Ok(x), Ok(y)] => {
                             let state = state.lock().unwrap();
-                            if state.room.is_none() {
-                                println!("Invalid place at command. e.g.: place at 0 0");
-                                None
-                            } else if !state.is_my_turn() {
-                                println!("Not your turn!");
-                                None
-                            } else if !state.game_state.started {
-                                println!("Still waiting for players!");
-                                None
-                            } else if x > 2 || y > 2 {
-                                println!("Invalid position(s)! Position must be between 0 and 2 inclusive");
-                                None
-                            } else if !state.can_place_at(x, y) {
-                                println!("Invalid position!");
-                                None
-                            } else {
-                                Some(MessageFromClient::GameAction(GameAction::PlaceNode(x, y)))
+                            match (&state.room, &state.username) {
+                                (Some(_), Some(username)) => {
+                                    match state.game_state.validate_place_node(x, y, username) {
+                                        Ok(()) => Some(MessageFromClient::game_action(
+                                            &GameAction::PlaceNode(x, y),
+                                        )?),
+                                        Err(rejection) => {
+                                            println!("{}", rejection);
+                                            None
+                                        }
+                                    }
+                                }
+                                _ => {
+                                    println!("Invalid place at command. e.g.: place at 0 0");
+                                    None
+                                }
                             }
                         }
                         _ => {
@@ -170,6 +345,14 @@ async fn send_tcp(mut tcp_write: OwnedWriteHalf, state: Arc<Mutex<State>>) -> Re
                             None
                         }
                     }
+                } else if let Some(id) = response.strip_prefix("replay ") {
+                    match id.trim().parse() {
+                        Ok(id) => Some(MessageFromClient::GetReplay(id)),
+                        Err(_) => {
+                            println!("Invalid replay command. e.g.: replay 1");
+                            None
+                        }
+                    }
                 } else {
                     println!("Invalid command");
                     None
@@ -189,6 +372,58 @@ async fn send_tcp(mut tcp_write: OwnedWriteHalf, state: Arc<Mutex<State>>) -> Re
     Ok(())
 }
 
+fn print_room_summary(room: &RoomSummary) {
+    let max_client = room
+        .max_client
+        .map_or_else(|| "-".to_string(), |max| max.to_string());
+    println!(
+        "{} [{}] {}/{} players, {} spectators, {}: {}",
+        room.name,
+        room.kind,
+        room.size,
+        max_client,
+        room.spectators,
+        if room.status.started {
+            "started"
+        } else {
+            "waiting"
+        },
+        room.status.players.join(", ")
+    );
+}
+
+fn print_replay_summary(replay: &ReplaySummary) {
+    let result = match replay.winner {
+        Some(player) => format!("{} won", replay.players[player as usize]),
+        None => "draw".to_string(),
+    };
+    println!(
+        "{}: {} in room {}, {}",
+        replay.id,
+        replay.players.join(" vs "),
+        replay.room_name,
+        result
+    );
+}
+
+fn print_player_stats(stats: &PlayerStats) {
+    println!(
+        "{} {:.0} ({} wins, {} losses, {} draws)",
+        stats.username, stats.rating, stats.wins, stats.losses, stats.draws
+    );
+}
+
+/// Parse whitespace separated key=value pairs, e.g. "width=4 height=4"
+fn parse_room_options(options: &str) -> RoomOptions {
+    options
+        .split_whitespace()
+        .filter_map(|option| {
+            let (key, value) = option.split_once('=')?;
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
 async fn prompt(prompt: Option<&str>) -> Result<String> {
     if let Some(prompt) = prompt {
         println!("{}", prompt);
@@ -1,13 +1,16 @@
-use dipa::Patchable;
 use shared::{
-    message::{GameState, Username},
+    message::{GameState, Outcome, Username},
+    replay::Replay,
     serialize::deserialize,
+    sync::apply_delta,
 };
 
 pub(crate) struct State {
     pub username: Option<Username>,
     pub room: Option<String>,
     pub game_state: GameState,
+    // replay being stepped through and the number of moves shown
+    pub replay: Option<(Box<Replay>, usize)>,
 }
 
 impl State {
@@ -16,33 +19,90 @@ impl State {
             username: None,
             room: None,
             game_state: GameState::default(),
+            replay: None,
+        }
+    }
+
+    /// Print the board of the replay after the moves shown so far
+    pub(crate) fn print_replay(&self) {
+        if let Some((replay, moves)) = &self.replay {
+            println!(
+                "Replay {} of room {} ({}), move {}/{}",
+                replay.id,
+                replay.room_name,
+                replay.players.join(" vs "),
+                moves,
+                replay.moves.len()
+            );
+            let step = State {
+                username: self.username.clone(),
+                room: None,
+                game_state: replay.state_after(*moves),
+                replay: None,
+            };
+            step.print_game_state();
+            if *moves < replay.moves.len() {
+                println!("type command \"next\" for the next move");
+            }
         }
     }
 
     pub(crate) fn print_game_state(&self) {
         if self.game_state.started {
+            if !self.game_state.disconnected.is_empty() {
+                println!(
+                    "Waiting for {} to reconnect...",
+                    self.game_state.disconnected.join(", ")
+                );
+            }
             // print board
-            if let Some(winner_idx) = self.game_state.winner {
-                let winner = &self.game_state.players[winner_idx as usize];
-                if winner == self.username.as_ref().unwrap() {
-                    println!("You won!",);
-                } else {
-                    println!("{} won!", winner);
+            match self.game_state.outcome() {
+                Outcome::Win { player, line } => {
+                    let winner = &self.game_state.players[player as usize];
+                    if winner == self.username.as_ref().unwrap() {
+                        println!("You won!",);
+                    } else {
+                        println!("{} won!", winner);
+                    }
+                    let line: Vec<_> = line.iter().map(|(x, y)| format!("{} {}", x, y)).collect();
+                    println!("winning line: {}", line.join(", "));
+                    println!("type command \"restart\" to restart");
                 }
-                println!("type command \"restart\" to restart");
-            } else if self.game_state.is_gameover() {
-                println!("Tied!");
-                println!("type command \"restart\" to restart");
-            } else {
-                let turn = &self.game_state.players[self.game_state.turn as usize];
-                if turn == self.username.as_ref().unwrap() {
-                    println!("Your turn!")
-                } else {
-                    println!("{}'s turn!", turn);
+                Outcome::TimedOut { player } => {
+                    let loser = &self.game_state.players[player as usize];
+                    if loser == self.username.as_ref().unwrap() {
+                        println!("You ran out of time!");
+                    } else {
+                        println!("{} ran out of time!", loser);
+                    }
+                    println!("type command \"restart\" to restart");
                 }
+                Outcome::Draw => {
+                    println!("Tied!");
+                    println!("type command \"restart\" to restart");
+                }
+                Outcome::InProgress => {
+                    let turn = &self.game_state.players[self.game_state.turn as usize];
+                    if turn == self.username.as_ref().unwrap() {
+                        println!("Your turn!")
+                    } else {
+                        println!("{}'s turn!", turn);
+                    }
+                }
+            }
+            if !self.game_state.time_left.is_empty() {
+                let clocks: Vec<_> = self
+                    .game_state
+                    .players
+                    .iter()
+                    .zip(&self.game_state.time_left)
+                    .map(|(player, millis)| format!("{} {}s", player, millis.div_ceil(1000)))
+                    .collect();
+                println!("time left: {}", clocks.join(", "));
             }
             // print board
-            println!("–––––––");
+            let separator = "–".repeat(self.game_state.width() * 2 + 1);
+            println!("{}", separator);
             for row in &self.game_state.board {
                 for block in row {
                     let c = match block {
@@ -54,7 +114,7 @@ impl State {
                     print!("|{}", c);
                 }
                 println!("|");
-                println!("–––––––");
+                println!("{}", separator);
             }
             println!();
         } else {
@@ -62,18 +122,11 @@ impl State {
         }
     }
 
-    pub(crate) fn can_place_at(&self, x: usize, y: usize) -> bool {
-        self.game_state.board[x][y] == -1
-    }
-
-    pub(crate) fn is_my_turn(&self) -> bool {
-        let turn = &self.game_state.players[self.game_state.turn as usize];
-        turn == self.username.as_ref().unwrap()
+    pub(crate) fn set_game_state(&mut self, bytes: Vec<u8>) {
+        self.game_state = deserialize(&bytes).expect("Failed to deserialize game state");
     }
 
     pub(crate) fn apply_patch(&mut self, bytes: Vec<u8>) {
-        let delta: <GameState as dipa::Diffable<'_, '_, GameState>>::DeltaOwned =
-            deserialize(&bytes).expect("Failed to deserialize game state");
-        self.game_state.apply_patch(delta);
+        apply_delta(&mut self.game_state, &bytes).expect("Failed to deserialize game state");
     }
 }
@@ -0,0 +1,135 @@
+use std::{fs, path::Path, sync::Arc};
+use tokio_rustls::{
+    rustls::{pki_types::CertificateDer, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use crate::Result;
+
+/// Connector trusting the certificates in the PEM file at `ca_cert`, e.g.
+/// the self-signed certificate of a server, or the usual web roots without
+/// one
+pub fn connector(ca_cert: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca_cert {
+        Some(path) => {
+            for cert in load_certificates(path)? {
+                roots.add(cert)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = fs::read(path)
+        .map_err(|err| format!("cannot read CA certificate {}: {}", path.display(), err))?;
+    let certs = rustls_pemfile::certs(&mut &pem[..]).collect::<std::io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::PathBuf;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+            ServerConfig,
+        },
+        TlsAcceptor,
+    };
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn self_signed() -> CertifiedKey {
+        generate_simple_self_signed(vec![LOCALHOST.to_string()]).unwrap()
+    }
+
+    /// Write the certificate to a file only used by the calling test
+    fn write_pem(certified: &CertifiedKey, name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("tic-tac-toe-{}-{}.pem", name, std::process::id()));
+        fs::write(&path, certified.cert.pem()).unwrap();
+        path
+    }
+
+    /// Accept a single TLS connection and echo what the client sends
+    async fn echo_server(certified: &CertifiedKey) -> u16 {
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = acceptor.accept(stream).await {
+                let mut buf = [0u8; 64];
+                let size = stream.read(&mut buf).await.unwrap();
+                stream.write_all(&buf[..size]).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn connect_with_ca_cert() {
+        let certified = self_signed();
+        let path = write_pem(&certified, "trusted");
+        let port = echo_server(&certified).await;
+
+        let connector = connector(Some(&path)).unwrap();
+        let stream = TcpStream::connect((LOCALHOST, port)).await.unwrap();
+        let mut stream = connector
+            .connect(ServerName::from(LOCALHOST), stream)
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut echo = Vec::new();
+        stream.read_to_end(&mut echo).await.unwrap();
+        assert_eq!(echo, b"hello");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_unknown_certificate() {
+        let path = write_pem(&self_signed(), "other");
+        let port = echo_server(&self_signed()).await;
+
+        let connector = connector(Some(&path)).unwrap();
+        let stream = TcpStream::connect((LOCALHOST, port)).await.unwrap();
+        assert!(connector
+            .connect(ServerName::from(LOCALHOST), stream)
+            .await
+            .is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_ca_cert_file() {
+        let path =
+            std::env::temp_dir().join(format!("tic-tac-toe-empty-{}.pem", std::process::id()));
+        fs::write(&path, "not a certificate").unwrap();
+        assert!(connector(Some(&path)).is_err());
+        assert!(connector(Some(Path::new("does-not-exist.pem"))).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use lunatic::{Mailbox, Request};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use shared::message::Username;
+use std::collections::HashMap;
+
+use crate::store;
+
+/// PBKDF2 rounds used for new passwords
+const HASH_ROUNDS: u32 = 100_000;
+const SALT_SIZE: usize = 16;
+const HASH_SIZE: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AuthRequest {
+    Register {
+        username: Username,
+        password: String,
+    }, // -> AccountCreated or AccountExists or InvalidCredentials
+    Verify {
+        username: Username,
+        password: String,
+    }, // -> Verified or InvalidCredentials
+    IsRegistered(Username), // -> IsRegistered
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AuthResponse {
+    AccountCreated,
+    AccountExists,
+    Verified,
+    InvalidCredentials,
+    IsRegistered(bool),
+}
+
+pub(crate) type AuthMsg = Request<AuthRequest, AuthResponse>;
+
+/// Salted password hash of an account, the password itself is never stored
+#[derive(Serialize, Deserialize)]
+struct Account {
+    salt: Vec<u8>,
+    rounds: u32,
+    hash: Vec<u8>,
+}
+
+impl Account {
+    fn new(password: &str) -> Self {
+        let salt = rand::random::<[u8; SALT_SIZE]>().to_vec();
+        let hash = hash_password(password, &salt, HASH_ROUNDS);
+        Self {
+            salt,
+            rounds: HASH_ROUNDS,
+            hash,
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        let hash = hash_password(password, &self.salt, self.rounds);
+        // compare every byte so that the time taken does not leak the hash
+        hash.len() == self.hash.len()
+            && hash
+                .iter()
+                .zip(&self.hash)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+fn hash_password(password: &str, salt: &[u8], rounds: u32) -> Vec<u8> {
+    let mut hash = vec![0u8; HASH_SIZE];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+/// Owns the account store, a bincode encoded map from username to account
+/// kept in the file at `path`. Hashing is slow on purpose, so it is done
+/// here rather than in the coordinator.
+pub fn auth_process(path: String, mailbox: Mailbox<AuthMsg>) {
+    let mut accounts: HashMap<Username, Account> = store::load(&path);
+
+    while let Ok(request) = mailbox.receive() {
+        let response = match request.data() {
+            AuthRequest::Register { username, password } => {
+                if username.is_empty() || password.is_empty() {
+                    AuthResponse::InvalidCredentials
+                } else if accounts.contains_key(username) {
+                    AuthResponse::AccountExists
+                } else {
+                    accounts.insert(username.clone(), Account::new(password));
+                    store::save(&path, &accounts);
+                    AuthResponse::AccountCreated
+                }
+            }
+            AuthRequest::Verify { username, password } => match accounts.get(username) {
+                Some(account) if account.verify(password) => AuthResponse::Verified,
+                _ => AuthResponse::InvalidCredentials,
+            },
+            AuthRequest::IsRegistered(username) => {
+                AuthResponse::IsRegistered(accounts.contains_key(username))
+            }
+        };
+        request.reply(response);
+    }
+}
@@ -0,0 +1,81 @@
+use lunatic::{
+    process::{self, Process},
+    Mailbox,
+};
+use shared::{
+    ai::{Difficulty, Solver},
+    message::{GameAction, GameState, MessageFromServer},
+    serialize::deserialize,
+    sync::apply_delta,
+};
+use tracing::warn;
+
+use crate::client::ClientMsg;
+use crate::logging::LogFilter;
+use crate::room::{Client, RoomMsg};
+
+/// Username of the bot, reserved so that no client can join with it
+pub const BOT_USERNAME: &str = "[bot]";
+
+/// A player controlled by the server. The room adds it as a client, it
+/// sends its moves through `RoomMsg::Action` so they are validated by the
+/// room exactly like the moves of a human player.
+pub fn bot_process(
+    (room, difficulty, log): (Process<RoomMsg>, Difficulty, LogFilter),
+    mailbox: Mailbox<ClientMsg>,
+) {
+    log.init();
+    let client = Client::new(BOT_USERNAME.to_string(), process::this(&mailbox));
+
+    let mut solver = Solver::new();
+    let mut rng = rand::thread_rng();
+    let mut state: Option<GameState> = None;
+    // turn and number of nodes of the last position a move was sent for,
+    // deltas of clocks or connections must not make the bot move again
+    let mut answered: Option<(u8, usize)> = None;
+
+    while let Ok(msg) = mailbox.receive() {
+        match msg {
+            ClientMsg::RoomMessage(MessageFromServer::State(bytes)) => {
+                state = deserialize(&bytes).ok();
+            }
+            ClientMsg::RoomMessage(MessageFromServer::StateChanged(bytes)) => {
+                if let Some(state) = state.as_mut() {
+                    let _ = apply_delta(state, &bytes);
+                }
+            }
+            ClientMsg::RoomMessage(MessageFromServer::ActionRejected(rejection)) => {
+                warn!(%rejection, "bot move rejected");
+                answered = None;
+                continue;
+            }
+            ClientMsg::ClientDropped => return,
+            _ => continue,
+        }
+
+        let state = match &state {
+            Some(state) if state.started && !state.is_gameover() => state,
+            _ => {
+                answered = None;
+                continue;
+            }
+        };
+        if state.players.get(state.turn as usize).map(String::as_str) != Some(BOT_USERNAME) {
+            continue;
+        }
+        // the game is paused until the opponent is back
+        if !state.disconnected.is_empty() {
+            continue;
+        }
+        let nodes = state.board.iter().flatten().filter(|&&node| node != -1);
+        let position = (state.turn, nodes.count());
+        if answered == Some(position) {
+            continue;
+        }
+        if let Some((x, y)) = solver.choose_move(state, difficulty, &mut rng) {
+            let action = bincode::serialize(&GameAction::PlaceNode(x, y)).unwrap();
+            room.send(RoomMsg::Action(client.clone(), action));
+            answered = Some(position);
+        }
+    }
+}
@@ -1,182 +1,552 @@
 use lunatic::{
-    net::TcpStream,
     process::{self, Process},
     Mailbox,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use shared::{
-    message::{MessageFromClient, MessageFromServer, Username},
-    serialize::*,
-};
-use std::{
-    io::{BufRead, BufReader},
-    time::Duration,
+    message::{ErrorCode, MessageFromClient, MessageFromServer, RoomName, Username},
+    transport::{Transport, TransportWriter},
 };
+use std::fmt::Debug;
+use tracing::{debug, info, warn};
 
+use crate::auth::{AuthMsg, AuthRequest, AuthResponse};
 use crate::coordinator::{CoordinatorMsg, CoordinatorRequest, CoordinatorResponse};
-use crate::room::{Client, RoomMsg};
+use crate::matchmaker::MatchmakerMsg;
+use crate::rating::{RatingRequest, RatingResponse};
+use crate::replay::{ReplayRequest, ReplayResponse};
+use crate::room::{Client, RoomMsg, Services};
+use crate::transport::reader_process;
 
 #[derive(Serialize, Deserialize)]
 pub enum ClientMsg {
     ClientDropped,
+    MalformedMessage(String),
     MessageFromClient(MessageFromClient),
     RoomMessage(MessageFromServer),
+    // the coordinator already added the client to the room, after
+    // matchmaking or when rejoining a room restored after a restart
+    RoomAssigned(RoomName, Process<RoomMsg>),
+    MatchmakerMessage(MessageFromServer),
+    // answer of the reader to the protocol, e.g. a WebSocket pong
+    Reply(Vec<u8>),
 }
 
-pub fn client_process(
-    (mut stream, coordinator): (TcpStream, Process<CoordinatorMsg>),
+pub fn client_process<T>(
+    (transport, coordinator, matchmaker, auth, services): (
+        T,
+        Process<CoordinatorMsg>,
+        Process<MatchmakerMsg>,
+        Process<AuthMsg>,
+        Services,
+    ),
     mailbox: Mailbox<ClientMsg>,
-) {
-    println!("client process created for stream: {:?}", stream);
+) where
+    T: Transport + Serialize + DeserializeOwned + Debug,
+    T::Reader: Serialize + DeserializeOwned,
+{
+    services.log.init();
+    let client_id = process::this(&mailbox).id();
+    info!(client_id, connection = ?transport, "client connected");
+    let (reader, writer) = transport.split();
+    let mut connection = Connection::new(writer, process::this(&mailbox));
 
     let mut current_room: Option<Process<RoomMsg>> = None;
 
     let mut username: Option<Username> = None;
 
+    // waiting in the matchmaking queue
+    let mut queued = false;
+
     // Spawn another actor to handle message reception and deserialization.
     // This actor will send the deserialized client message to the client's
     // (this) mailbox
     let client_proc = process::this(&mailbox);
-    let (_, mailbox) = process::spawn_link_unwrap_with(
-        mailbox,
-        (client_proc, stream.clone()),
-        |(client, mut stream), _: Mailbox<()>| {
-            // set five minute timeout
-            stream.set_read_timeout(Some(Duration::new(5 * 60, 0)));
-
-            let mut reader = BufReader::new(stream.clone());
-            let mut buffer = String::new();
-
-            loop {
-                if let Ok(size) = reader.read_line(&mut buffer) {
-                    if size == 0 {
-                        client.send(ClientMsg::ClientDropped);
-                        break;
-                    }
-
-                    let msg: MessageFromClient = read_serialized(buffer.as_bytes()).unwrap();
-                    let should_break = matches!(msg, MessageFromClient::LeaveServer);
-                    client.send(ClientMsg::MessageFromClient(msg));
-                    if should_break {
-                        break;
-                    }
-
-                    buffer.clear();
-                } else {
-                    // read timeout
-                    client.send(ClientMsg::ClientDropped);
-                    break;
-                }
-            }
-        },
-    )
-    .unwrap();
+    let (_, mailbox) =
+        process::spawn_link_unwrap_with(mailbox, (client_proc, reader), reader_process).unwrap();
 
     while let Ok(msg) = mailbox.receive() {
         match msg {
             ClientMsg::ClientDropped => {
+                info!(client_id, username = username.as_deref(), "client dropped");
+                if queued {
+                    matchmaker.send(MatchmakerMsg::CancelMatch(process::this(&mailbox)));
+                }
+                // keep the session around so that the client can resume it
                 if username.is_some() {
-                    coordinator
-                        .request(CoordinatorRequest::LeaveServer)
-                        .unwrap();
+                    coordinator.request(CoordinatorRequest::Disconnect).unwrap();
                 }
                 // do not go though the regular leave server procedure
+                connection.close();
                 return;
             }
-            ClientMsg::MessageFromClient(client_msg) => match client_msg {
-                MessageFromClient::JoinServer(username_) => {
-                    match coordinator
-                        .request(CoordinatorRequest::JoinServer(username_.clone()))
-                        .unwrap()
-                    {
-                        CoordinatorResponse::ServerJoined => {
+            ClientMsg::MalformedMessage(reason) => {
+                warn!(
+                    client_id,
+                    username = username.as_deref(),
+                    %reason,
+                    "malformed message"
+                );
+                connection.send(MessageFromServer::error(
+                    ErrorCode::MalformedMessage,
+                    reason,
+                ));
+            }
+            ClientMsg::MessageFromClient(client_msg) => {
+                debug!(
+                    client_id,
+                    username = username.as_deref(),
+                    kind = client_msg.kind(),
+                    "message from client"
+                );
+                if let Err(error) = check_session_state(
+                    &client_msg,
+                    username.is_some(),
+                    current_room.is_some(),
+                    queued,
+                ) {
+                    connection.send(error);
+                    continue;
+                }
+
+                match client_msg {
+                    MessageFromClient::JoinServer(username_) => {
+                        // guests cannot take the name of a registered account
+                        let response = match auth
+                            .request(AuthRequest::IsRegistered(username_.clone()))
+                            .unwrap()
+                        {
+                            AuthResponse::IsRegistered(true) => {
+                                MessageFromServer::UsernameAlreadyTaken
+                            }
+                            _ => join_server(
+                                &coordinator,
+                                &username_,
+                                process::this(&mailbox),
+                                false,
+                            ),
+                        };
+                        if let MessageFromServer::ServerJoined(_) = response {
+                            info!(client_id, username = %username_, "joined server");
                             username = Some(username_);
-                            write_serialized(MessageFromServer::ServerJoined, &mut stream).unwrap();
                         }
-                        CoordinatorResponse::UsernameAlreadyTaken => {
-                            write_serialized(MessageFromServer::UsernameAlreadyTaken, &mut stream)
-                                .unwrap();
+                        connection.send(response);
+                    }
+                    MessageFromClient::Register {
+                        username: username_,
+                        password,
+                    } => {
+                        // claim the name first so that a guest using it cannot
+                        // be registered from under them
+                        let mut response =
+                            join_server(&coordinator, &username_, process::this(&mailbox), false);
+                        if let MessageFromServer::ServerJoined(_) = response {
+                            match auth
+                                .request(AuthRequest::Register {
+                                    username: username_.clone(),
+                                    password,
+                                })
+                                .unwrap()
+                            {
+                                AuthResponse::AccountCreated => {
+                                    coordinator.request(CoordinatorRequest::Registered).unwrap();
+                                    info!(client_id, username = %username_, "registered");
+                                    username = Some(username_);
+                                }
+                                rejection => {
+                                    coordinator
+                                        .request(CoordinatorRequest::LeaveServer)
+                                        .unwrap();
+                                    response = match rejection {
+                                        AuthResponse::AccountExists => {
+                                            MessageFromServer::UsernameAlreadyTaken
+                                        }
+                                        _ => MessageFromServer::InvalidCredentials,
+                                    };
+                                }
+                            }
                         }
-                        _ => unreachable!(),
+                        connection.send(response);
                     }
-                }
-                MessageFromClient::LeaveServer => break,
-                MessageFromClient::CreateRoom(room_name) => {
-                    match coordinator
-                        .request(CoordinatorRequest::CreateRoom(
-                            room_name,
-                            process::this(&mailbox),
-                        ))
-                        .unwrap()
-                    {
-                        CoordinatorResponse::RoomCreated(room_proc) => {
-                            if current_room.is_some() {
-                                panic!("client is creating a new room when it is already in a existing room")
-                            };
-                            current_room = Some(room_proc);
-                            write_serialized(MessageFromServer::RoomCreated, &mut stream).unwrap();
+                    MessageFromClient::Login {
+                        username: username_,
+                        password,
+                    } => {
+                        let response = match auth
+                            .request(AuthRequest::Verify {
+                                username: username_.clone(),
+                                password,
+                            })
+                            .unwrap()
+                        {
+                            // the account may still be logged in elsewhere
+                            AuthResponse::Verified => {
+                                join_server(&coordinator, &username_, process::this(&mailbox), true)
+                            }
+                            _ => MessageFromServer::InvalidCredentials,
+                        };
+                        if let MessageFromServer::ServerJoined(_) = response {
+                            info!(client_id, username = %username_, "joined server");
+                            username = Some(username_);
                         }
-                        CoordinatorResponse::RoomNameAlreadyTaken => {
-                            write_serialized(MessageFromServer::RoomNameAlreadyTaken, &mut stream)
-                                .unwrap();
+                        connection.send(response);
+                    }
+                    MessageFromClient::Resume(token) => {
+                        match coordinator
+                            .request(CoordinatorRequest::Resume(token, process::this(&mailbox)))
+                            .unwrap()
+                        {
+                            CoordinatorResponse::SessionResumed {
+                                username: username_,
+                                room,
+                            } => {
+                                info!(client_id, username = %username_, "resumed session");
+                                username = Some(username_.clone());
+                                let room_name = room.map(|(room_name, room_proc)| {
+                                    current_room = Some(room_proc);
+                                    room_name
+                                });
+                                connection.send(MessageFromServer::SessionResumed {
+                                    username: username_,
+                                    room: room_name,
+                                });
+                            }
+                            CoordinatorResponse::SessionNotFound => {
+                                connection.send(MessageFromServer::SessionNotFound);
+                            }
+                            response => connection.send(error_response(response)),
                         }
-                        _ => unreachable!(),
                     }
-                }
-                MessageFromClient::JoinRoom(room_name) => {
-                    match coordinator
-                        .request(CoordinatorRequest::JoinRoom(
-                            room_name,
-                            process::this(&mailbox),
-                        ))
-                        .unwrap()
-                    {
-                        CoordinatorResponse::RoomJoined(room_proc) => {
-                            current_room = Some(room_proc);
-                            write_serialized::<MessageFromServer, TcpStream>(
-                                MessageFromServer::RoomJoined,
-                                &mut stream,
-                            )
-                            .unwrap();
+                    MessageFromClient::LeaveServer => break,
+                    MessageFromClient::CreateRoom {
+                        name,
+                        kind,
+                        options,
+                    } => {
+                        match coordinator
+                            .request(CoordinatorRequest::CreateRoom {
+                                name,
+                                kind,
+                                options,
+                                client: process::this(&mailbox),
+                            })
+                            .unwrap()
+                        {
+                            CoordinatorResponse::RoomCreated(room_proc) => {
+                                current_room = Some(room_proc);
+                                connection.send(MessageFromServer::RoomCreated);
+                            }
+                            CoordinatorResponse::RoomNameAlreadyTaken => {
+                                connection.send(MessageFromServer::RoomNameAlreadyTaken);
+                            }
+                            CoordinatorResponse::RoomKindDoesNotExist => {
+                                connection.send(MessageFromServer::RoomKindDoesNotExist);
+                            }
+                            CoordinatorResponse::RoomLimitReached => {
+                                connection.send(MessageFromServer::RoomLimitReached);
+                            }
+                            response => connection.send(error_response(response)),
                         }
-                        CoordinatorResponse::RoomFull => {
-                            write_serialized(MessageFromServer::RoomFull, &mut stream).unwrap();
+                    }
+                    MessageFromClient::JoinRoom(room_name) => {
+                        match coordinator
+                            .request(CoordinatorRequest::JoinRoom(
+                                room_name.clone(),
+                                process::this(&mailbox),
+                            ))
+                            .unwrap()
+                        {
+                            CoordinatorResponse::RoomJoined(room_proc) => {
+                                current_room = Some(room_proc);
+                                connection.send(MessageFromServer::RoomJoined(room_name));
+                            }
+                            CoordinatorResponse::RoomFull => {
+                                connection.send(MessageFromServer::RoomFull);
+                            }
+                            CoordinatorResponse::RoomDoesNotExist => {
+                                connection.send(MessageFromServer::RoomDoesNotExist);
+                            }
+                            response => connection.send(error_response(response)),
                         }
-                        CoordinatorResponse::RoomDoesNotExist => {
-                            write_serialized(MessageFromServer::RoomDoesNotExist, &mut stream)
-                                .unwrap();
+                    }
+                    MessageFromClient::SpectateRoom(room_name) => {
+                        match coordinator
+                            .request(CoordinatorRequest::SpectateRoom(
+                                room_name,
+                                process::this(&mailbox),
+                            ))
+                            .unwrap()
+                        {
+                            CoordinatorResponse::RoomSpectated(room_proc) => {
+                                current_room = Some(room_proc);
+                                connection.send(MessageFromServer::RoomSpectated);
+                            }
+                            CoordinatorResponse::RoomDoesNotExist => {
+                                connection.send(MessageFromServer::RoomDoesNotExist);
+                            }
+                            response => connection.send(error_response(response)),
                         }
-                        _ => unreachable!(),
                     }
-                }
-                MessageFromClient::LeaveRoom => {
-                    current_room = None;
-                    coordinator
-                        .request(CoordinatorRequest::LeaveRoom(process::this(&mailbox)))
-                        .unwrap();
-                }
-                MessageFromClient::GameAction(action) => {
-                    match &current_room {
-                        Some(room) => {
+                    MessageFromClient::LeaveRoom => {
+                        match coordinator
+                            .request(CoordinatorRequest::LeaveRoom(process::this(&mailbox)))
+                            .unwrap()
+                        {
+                            CoordinatorResponse::RoomLeft => current_room = None,
+                            response => connection.send(error_response(response)),
+                        }
+                    }
+                    MessageFromClient::ListRooms(filter) => {
+                        match coordinator
+                            .request(CoordinatorRequest::ListRooms(filter))
+                            .unwrap()
+                        {
+                            CoordinatorResponse::RoomList(rooms) => {
+                                connection.send(MessageFromServer::RoomList(rooms));
+                            }
+                            response => connection.send(error_response(response)),
+                        }
+                    }
+                    MessageFromClient::FindMatch(kind) => {
+                        queued = true;
+                        matchmaker.send(MatchmakerMsg::FindMatch(kind, process::this(&mailbox)));
+                    }
+                    MessageFromClient::CancelMatch => {
+                        matchmaker.send(MatchmakerMsg::CancelMatch(process::this(&mailbox)));
+                    }
+                    MessageFromClient::GetLeaderboard { top_n } => {
+                        match services
+                            .rating
+                            .request(RatingRequest::GetLeaderboard(top_n as usize))
+                            .unwrap()
+                        {
+                            RatingResponse::Leaderboard(players) => {
+                                connection.send(MessageFromServer::Leaderboard(players));
+                            }
+                            response => {
+                                unreachable!("unexpected rating response {:?}", response)
+                            }
+                        }
+                    }
+                    MessageFromClient::GetPlayerStats(username) => {
+                        match services
+                            .rating
+                            .request(RatingRequest::GetPlayerStats(username))
+                            .unwrap()
+                        {
+                            RatingResponse::PlayerStats(Some(stats)) => {
+                                connection.send(MessageFromServer::PlayerStats(stats));
+                            }
+                            RatingResponse::PlayerStats(None) => {
+                                connection.send(MessageFromServer::PlayerNotFound);
+                            }
+                            response => {
+                                unreachable!("unexpected rating response {:?}", response)
+                            }
+                        }
+                    }
+                    MessageFromClient::GetReplay(id) => {
+                        match services.replays.request(ReplayRequest::Get(id)).unwrap() {
+                            ReplayResponse::Replay(Some(replay)) => {
+                                connection.send(MessageFromServer::Replay(Box::new(replay)));
+                            }
+                            ReplayResponse::Replay(None) => {
+                                connection.send(MessageFromServer::ReplayNotFound);
+                            }
+                            response => {
+                                unreachable!("unexpected replay response {:?}", response)
+                            }
+                        }
+                    }
+                    MessageFromClient::ListReplays(username) => {
+                        match services
+                            .replays
+                            .request(ReplayRequest::List(username))
+                            .unwrap()
+                        {
+                            ReplayResponse::ReplayList(replays) => {
+                                connection.send(MessageFromServer::ReplayList(replays));
+                            }
+                            response => {
+                                unreachable!("unexpected replay response {:?}", response)
+                            }
+                        }
+                    }
+                    MessageFromClient::GameAction(action) => {
+                        if let (Some(room), Some(username)) = (&current_room, &username) {
                             room.send(RoomMsg::Action(
-                                Client::new(username.clone().unwrap(), process::this(&mailbox)),
+                                Client::new(username.clone(), process::this(&mailbox)),
                                 action,
                             ));
                         }
-                        None => panic!("client cannot execute game actions before joining a room"),
-                    };
+                    }
                 }
-            },
+            }
             ClientMsg::RoomMessage(room_msg) => {
-                write_serialized(room_msg, &mut stream).unwrap();
+                connection.send(room_msg);
+            }
+            ClientMsg::RoomAssigned(room_name, room_proc) => {
+                queued = false;
+                current_room = Some(room_proc);
+                connection.send(MessageFromServer::RoomJoined(room_name));
             }
+            ClientMsg::MatchmakerMessage(msg) => {
+                queued = matches!(msg, MessageFromServer::MatchQueued);
+                connection.send(msg);
+            }
+            ClientMsg::Reply(reply) => connection.write_reply(&reply),
         }
     }
 
+    if queued {
+        matchmaker.send(MatchmakerMsg::CancelMatch(process::this(&mailbox)));
+    }
     // only request leave server if client has joined server in the first place
     if username.is_some() {
         coordinator
             .request(CoordinatorRequest::LeaveServer)
             .unwrap();
     }
+    info!(client_id, username = username.as_deref(), "client left");
+    connection.close();
+}
+
+/// Writer half of the connection of a client. A failed write drops the client
+/// like a closed connection, later writes are skipped.
+struct Connection<W> {
+    writer: W,
+    client: Process<ClientMsg>,
+    broken: bool,
+}
+
+impl<W: TransportWriter> Connection<W> {
+    fn new(writer: W, client: Process<ClientMsg>) -> Self {
+        Self {
+            writer,
+            client,
+            broken: false,
+        }
+    }
+
+    fn send(&mut self, msg: MessageFromServer) {
+        if !self.broken && self.writer.send(msg).is_err() {
+            self.drop_client();
+        }
+    }
+
+    fn write_reply(&mut self, reply: &[u8]) {
+        if !self.broken && self.writer.write_reply(reply).is_err() {
+            self.drop_client();
+        }
+    }
+
+    fn drop_client(&mut self) {
+        self.broken = true;
+        self.client.send(ClientMsg::ClientDropped);
+    }
+
+    fn close(&mut self) {
+        self.writer.close();
+    }
+}
+
+/// Reject messages that are not valid in the current state of the session
+/// before they reach the coordinator or the room.
+fn check_session_state(
+    msg: &MessageFromClient,
+    joined_server: bool,
+    in_room: bool,
+    queued: bool,
+) -> Result<(), MessageFromServer> {
+    match msg {
+        MessageFromClient::JoinServer(_)
+        | MessageFromClient::Register { .. }
+        | MessageFromClient::Login { .. }
+        | MessageFromClient::Resume(_)
+            if joined_server =>
+        {
+            Err(MessageFromServer::error(
+                ErrorCode::AlreadyJoinedServer,
+                "already joined the server",
+            ))
+        }
+        MessageFromClient::JoinServer(_)
+        | MessageFromClient::Register { .. }
+        | MessageFromClient::Login { .. }
+        | MessageFromClient::Resume(_)
+        | MessageFromClient::LeaveServer => Ok(()),
+        _ if !joined_server => Err(MessageFromServer::error(
+            ErrorCode::NotJoinedServer,
+            "join the server first",
+        )),
+        MessageFromClient::CreateRoom { .. }
+        | MessageFromClient::JoinRoom(_)
+        | MessageFromClient::SpectateRoom(_)
+        | MessageFromClient::FindMatch(_)
+            if queued =>
+        {
+            Err(MessageFromServer::error(
+                ErrorCode::AlreadyInQueue,
+                "cancel matchmaking first",
+            ))
+        }
+        MessageFromClient::CancelMatch if !queued => Err(MessageFromServer::error(
+            ErrorCode::NotInQueue,
+            "not looking for a match",
+        )),
+        MessageFromClient::CreateRoom { .. }
+        | MessageFromClient::JoinRoom(_)
+        | MessageFromClient::SpectateRoom(_)
+        | MessageFromClient::FindMatch(_)
+            if in_room =>
+        {
+            Err(MessageFromServer::error(
+                ErrorCode::AlreadyInRoom,
+                "leave the current room first",
+            ))
+        }
+        MessageFromClient::LeaveRoom | MessageFromClient::GameAction(_) if !in_room => Err(
+            MessageFromServer::error(ErrorCode::NotInRoom, "join a room first"),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Ask the coordinator for a new session under `username`, `logged_in` if
+/// the client proved it owns the account of that name
+fn join_server(
+    coordinator: &Process<CoordinatorMsg>,
+    username: &Username,
+    client: Process<ClientMsg>,
+    logged_in: bool,
+) -> MessageFromServer {
+    match coordinator
+        .request(CoordinatorRequest::JoinServer {
+            username: username.clone(),
+            client,
+            logged_in,
+        })
+        .unwrap()
+    {
+        CoordinatorResponse::ServerJoined(token) => MessageFromServer::ServerJoined(token),
+        CoordinatorResponse::UsernameAlreadyTaken => MessageFromServer::UsernameAlreadyTaken,
+        CoordinatorResponse::ServerFull => MessageFromServer::ServerFull,
+        response => error_response(response),
+    }
+}
+
+/// Translate a refusal from the coordinator into an error for the client
+fn error_response(response: CoordinatorResponse) -> MessageFromServer {
+    match response {
+        CoordinatorResponse::NotJoinedServer => {
+            MessageFromServer::error(ErrorCode::NotJoinedServer, "join the server first")
+        }
+        CoordinatorResponse::AlreadyInRoom => {
+            MessageFromServer::error(ErrorCode::AlreadyInRoom, "leave the current room first")
+        }
+        CoordinatorResponse::NotInRoom => {
+            MessageFromServer::error(ErrorCode::NotInRoom, "join a room first")
+        }
+        response => unreachable!("unexpected coordinator response {:?}", response),
+    }
 }
@@ -0,0 +1,421 @@
+use serde::{Deserialize, Serialize};
+use shared::message::{RoomKind, RoomOptions, DEFAULT_ROOM_KIND};
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use structopt::StructOpt;
+
+use crate::logging::LogFilter;
+
+/// Read if it exists and no other file is given
+const DEFAULT_CONFIG_PATH: &str = "server.toml";
+
+/// Settings of the server. Every setting has a default, which is overridden
+/// by the config file, then by environment variables and finally by command
+/// line flags.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub limits: Limits,
+    pub rooms: RoomsConfig,
+    pub storage: StorageConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    // length-prefixed frames
+    pub tcp_address: String,
+    pub websocket_address: String,
+    // TLS is only enabled with an address
+    pub tls_address: Option<String>,
+    pub tls_cert: String,
+    pub tls_key: String,
+    // seconds a client can stay silent before it is dropped
+    pub read_timeout: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            tcp_address: "127.0.0.1:1337".to_string(),
+            websocket_address: "127.0.0.1:1338".to_string(),
+            tls_address: None,
+            tls_cert: "cert.pem".to_string(),
+            tls_key: "key.pem".to_string(),
+            read_timeout: 5 * 60,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout)
+    }
+}
+
+/// Enforced by the coordinator
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_rooms: usize,
+    // clients joined to the server, including dropped sessions that can
+    // still be resumed
+    pub max_clients: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_rooms: 1000,
+            max_clients: 10_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    // used when a client asks for a room without a kind
+    pub default_kind: RoomKind,
+    // seconds the seat of a disconnected player is kept, unless the room
+    // options say otherwise
+    pub reservation_timeout: u64,
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        Self {
+            default_kind: DEFAULT_ROOM_KIND.to_string(),
+            reservation_timeout: 60,
+        }
+    }
+}
+
+impl RoomsConfig {
+    pub fn kind_or_default(&self, kind: &str) -> RoomKind {
+        if kind.is_empty() {
+            self.default_kind.clone()
+        } else {
+            kind.to_string()
+        }
+    }
+
+    /// Fill in the server wide defaults the client did not override
+    pub fn apply_defaults(&self, options: &mut RoomOptions) {
+        options
+            .entry("reservation_timeout".to_string())
+            .or_insert_with(|| self.reservation_timeout.to_string());
+    }
+}
+
+/// Files and directories relative to the working directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub accounts: String,
+    pub ratings: String,
+    pub replays: String,
+    pub snapshots: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            accounts: "accounts.bin".to_string(),
+            ratings: "ratings.bin".to_string(),
+            replays: "replays".to_string(),
+            snapshots: "rooms.bin".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // a level, optionally followed by levels of single modules, e.g.
+    // `info,server::coordinator=debug`
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn filter(&self) -> Result<LogFilter, String> {
+        LogFilter::new(&self.level)
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "tic tac toe server", about = "A tic tac toe server")]
+struct Opt {
+    /// TOML config file, `server.toml` is read if it exists
+    #[structopt(long, env = "TIC_TAC_TOE_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_TCP_ADDRESS")]
+    tcp_address: Option<String>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_WEBSOCKET_ADDRESS")]
+    websocket_address: Option<String>,
+
+    /// Enables TLS
+    #[structopt(long, env = "TIC_TAC_TOE_TLS_ADDRESS")]
+    tls_address: Option<String>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_TLS_CERT")]
+    tls_cert: Option<String>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_TLS_KEY")]
+    tls_key: Option<String>,
+
+    /// Seconds
+    #[structopt(long, env = "TIC_TAC_TOE_READ_TIMEOUT")]
+    read_timeout: Option<u64>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_MAX_ROOMS")]
+    max_rooms: Option<usize>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_MAX_CLIENTS")]
+    max_clients: Option<usize>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_DEFAULT_ROOM_KIND")]
+    default_room_kind: Option<RoomKind>,
+
+    /// Seconds
+    #[structopt(long, env = "TIC_TAC_TOE_RESERVATION_TIMEOUT")]
+    reservation_timeout: Option<u64>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_ACCOUNTS_PATH")]
+    accounts_path: Option<String>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_RATINGS_PATH")]
+    ratings_path: Option<String>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_REPLAYS_DIR")]
+    replays_dir: Option<String>,
+
+    #[structopt(long, env = "TIC_TAC_TOE_SNAPSHOTS_PATH")]
+    snapshots_path: Option<String>,
+
+    /// E.g. `info,server::coordinator=debug`
+    #[structopt(long, env = "TIC_TAC_TOE_LOG_LEVEL")]
+    log_level: Option<String>,
+}
+
+impl Config {
+    /// Read the config file and apply the overrides of the environment and
+    /// the command line, then check the result
+    pub fn load() -> Result<Self, String> {
+        let opt = Opt::from_args();
+        let mut config = match &opt.config {
+            Some(path) => Self::read(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::read(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+        config.apply(opt);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        toml::from_str(&text).map_err(|err| format!("invalid {}: {}", path.display(), err))
+    }
+
+    fn apply(&mut self, opt: Opt) {
+        fn set<T>(setting: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *setting = value;
+            }
+        }
+        set(&mut self.network.tcp_address, opt.tcp_address);
+        set(&mut self.network.websocket_address, opt.websocket_address);
+        if opt.tls_address.is_some() {
+            self.network.tls_address = opt.tls_address;
+        }
+        set(&mut self.network.tls_cert, opt.tls_cert);
+        set(&mut self.network.tls_key, opt.tls_key);
+        set(&mut self.network.read_timeout, opt.read_timeout);
+        set(&mut self.limits.max_rooms, opt.max_rooms);
+        set(&mut self.limits.max_clients, opt.max_clients);
+        set(&mut self.rooms.default_kind, opt.default_room_kind);
+        set(&mut self.rooms.reservation_timeout, opt.reservation_timeout);
+        set(&mut self.storage.accounts, opt.accounts_path);
+        set(&mut self.storage.ratings, opt.ratings_path);
+        set(&mut self.storage.replays, opt.replays_dir);
+        set(&mut self.storage.snapshots, opt.snapshots_path);
+        set(&mut self.log.level, opt.log_level);
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let network = &self.network;
+        let mut addresses = vec![
+            ("network.tcp_address", &network.tcp_address),
+            ("network.websocket_address", &network.websocket_address),
+        ];
+        if let Some(tls_address) = &network.tls_address {
+            addresses.push(("network.tls_address", tls_address));
+        }
+        let mut bound = HashSet::new();
+        for (name, address) in addresses {
+            let address: SocketAddr = address
+                .parse()
+                .map_err(|_| format!("{}: invalid address {:?}", name, address))?;
+            if !bound.insert(address) {
+                return Err(format!("{}: {} is used twice", name, address));
+            }
+        }
+        if network.read_timeout == 0 {
+            return Err("network.read_timeout: must be at least one second".to_string());
+        }
+
+        if self.limits.max_rooms == 0 {
+            return Err("limits.max_rooms: must be at least 1".to_string());
+        }
+        if self.limits.max_clients == 0 {
+            return Err("limits.max_clients: must be at least 1".to_string());
+        }
+
+        if crate::room_registry()
+            .options(&self.rooms.default_kind, &RoomOptions::new())
+            .is_none()
+        {
+            return Err(format!(
+                "rooms.default_kind: unknown room kind {:?}",
+                self.rooms.default_kind
+            ));
+        }
+        if self.rooms.reservation_timeout == 0 {
+            return Err("rooms.reservation_timeout: must be at least one second".to_string());
+        }
+        if self.rooms.reservation_timeout > crate::MAX_RESERVATION_TIMEOUT {
+            return Err(format!(
+                "rooms.reservation_timeout: must be at most {} seconds",
+                crate::MAX_RESERVATION_TIMEOUT
+            ));
+        }
+
+        let storage = &self.storage;
+        let mut paths = HashSet::new();
+        for (name, path) in [
+            ("storage.accounts", &storage.accounts),
+            ("storage.ratings", &storage.ratings),
+            ("storage.replays", &storage.replays),
+            ("storage.snapshots", &storage.snapshots),
+        ] {
+            if path.is_empty() {
+                return Err(format!("{}: path is empty", name));
+            }
+            if !paths.insert(path) {
+                return Err(format!("{}: {} is used twice", name, path));
+            }
+        }
+
+        self.log
+            .filter()
+            .map_err(|err| format!("log.level: {}", err))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(change: impl FnOnce(&mut Config)) -> String {
+        let mut config = Config::default();
+        change(&mut config);
+        config.validate().unwrap_err()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn example_file_is_valid() {
+        let config: Config = toml::from_str(include_str!("../server.example.toml")).unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_and_duplicate_addresses() {
+        assert_eq!(
+            error(|config| config.network.tcp_address = "localhost".to_string()),
+            "network.tcp_address: invalid address \"localhost\""
+        );
+        assert_eq!(
+            error(|config| config.network.websocket_address = config.network.tcp_address.clone()),
+            "network.websocket_address: 127.0.0.1:1337 is used twice"
+        );
+        assert_eq!(
+            error(|config| config.network.tls_address = Some("127.0.0.1:1338".to_string())),
+            "network.tls_address: 127.0.0.1:1338 is used twice"
+        );
+    }
+
+    #[test]
+    fn reject_zero_timeouts_and_limits() {
+        assert_eq!(
+            error(|config| config.network.read_timeout = 0),
+            "network.read_timeout: must be at least one second"
+        );
+        assert_eq!(
+            error(|config| config.rooms.reservation_timeout = 0),
+            "rooms.reservation_timeout: must be at least one second"
+        );
+        assert_eq!(
+            error(|config| config.rooms.reservation_timeout = u64::MAX),
+            "rooms.reservation_timeout: must be at most 86400 seconds"
+        );
+        assert_eq!(
+            error(|config| config.limits.max_rooms = 0),
+            "limits.max_rooms: must be at least 1"
+        );
+        assert_eq!(
+            error(|config| config.limits.max_clients = 0),
+            "limits.max_clients: must be at least 1"
+        );
+    }
+
+    #[test]
+    fn reject_unknown_default_kind() {
+        assert_eq!(
+            error(|config| config.rooms.default_kind = "chess".to_string()),
+            "rooms.default_kind: unknown room kind \"chess\""
+        );
+    }
+
+    #[test]
+    fn reject_empty_and_duplicate_storage_paths() {
+        assert_eq!(
+            error(|config| config.storage.ratings = String::new()),
+            "storage.ratings: path is empty"
+        );
+        assert_eq!(
+            error(|config| config.storage.snapshots = config.storage.accounts.clone()),
+            "storage.snapshots: accounts.bin is used twice"
+        );
+    }
+
+    #[test]
+    fn reject_bad_log_directive() {
+        let err = error(|config| config.log.level = "server=loud".to_string());
+        assert!(err.starts_with("log.level: "), "{}", err);
+    }
+}
@@ -1,35 +1,113 @@
+use crate::bot::BOT_USERNAME;
 use crate::client::ClientMsg;
-use crate::room::{room_process, Client, Room, RoomMsg};
+use crate::config::{Limits, RoomsConfig};
+use crate::room::{Client, RoomMsg, Services};
+use crate::snapshot::{SnapshotRequest, SnapshotResponse};
 
 use lunatic::{
-    process::{spawn_with, Process},
+    process::{self, Process},
     Mailbox, Message, Request, Tag, TransformMailbox,
 };
 use serde::{Deserialize, Serialize};
-use shared::message::Username;
+use shared::message::{
+    RoomFilter, RoomKind, RoomName, RoomOptions, RoomStatus, RoomSummary, SessionToken, Username,
+};
 use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// How long the session of a dropped client is kept for it to resume. Seats
+/// in rooms are kept as long as the room reserves them.
+const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(60);
 
-pub type RoomName = String;
 pub type RoomSize = usize; // number of clients in the room
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CoordinatorRequest {
     // Server related messages
-    JoinServer(Username), // -> ServerJoined or UsernameAlreadyTaken
-    LeaveServer,          // -> no response
+    // takes back the seat of the username in a room restored after a restart
+    // if the client logged in to the account of that name, anyone else is
+    // refused the name while the seat is kept
+    // -> ServerJoined or UsernameAlreadyTaken or ServerFull
+    JoinServer {
+        username: Username,
+        client: Process<ClientMsg>,
+        logged_in: bool,
+    },
+    LeaveServer, // -> no response
+    // the client created the account of its username after joining
+    Registered, // -> LoggedIn
+    // the connection of the client dropped, keep its session for a while
+    Disconnect,                               // -> Disconnected
+    Resume(SessionToken, Process<ClientMsg>), // -> SessionResumed or SessionNotFound
+    // sent once the grace period of a dropped session is over
+    ExpireSession(SessionToken), // -> SessionExpired
 
     // Room related messages
     JoinRoom(RoomName, Process<ClientMsg>), // -> RoomJoined or RoomFull or RoomDoesNotExist
-    CreateRoom(String, Process<ClientMsg>), // -> RoomCreated or RoomNameAlreadyTaken
+    SpectateRoom(RoomName, Process<ClientMsg>), // -> RoomSpectated or RoomDoesNotExist
+    // -> RoomCreated or RoomNameAlreadyTaken or RoomKindDoesNotExist or
+    // RoomLimitReached, an empty kind stands for the default kind
+    CreateRoom {
+        name: RoomName,
+        kind: RoomKind,
+        options: RoomOptions,
+        client: Process<ClientMsg>,
+    },
     LeaveRoom(Process<ClientMsg>),
+    ListRooms(RoomFilter), // -> RoomList
+
+    // Sent by room processes
+    UpdateRoomStatus(RoomStatus), // -> RoomStatusUpdated
+    // the seat reserved for a disconnected client was given up
+    ReleaseSeat(Username), // -> SeatReleased
+
+    // Sent by the matchmaker, creates a room with a generated name for the
+    // given clients -> MatchCreated or PlayersUnavailable or RoomKindDoesNotExist
+    // or RoomLimitReached
+    CreateMatch {
+        kind: RoomKind,
+        clients: Vec<Process<ClientMsg>>,
+    },
+}
+
+impl CoordinatorRequest {
+    /// Name of the variant for the log, without the processes it carries
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CoordinatorRequest::JoinServer { .. } => "JoinServer",
+            CoordinatorRequest::LeaveServer => "LeaveServer",
+            CoordinatorRequest::Registered => "Registered",
+            CoordinatorRequest::Disconnect => "Disconnect",
+            CoordinatorRequest::Resume(..) => "Resume",
+            CoordinatorRequest::ExpireSession(_) => "ExpireSession",
+            CoordinatorRequest::JoinRoom(..) => "JoinRoom",
+            CoordinatorRequest::SpectateRoom(..) => "SpectateRoom",
+            CoordinatorRequest::CreateRoom { .. } => "CreateRoom",
+            CoordinatorRequest::LeaveRoom(_) => "LeaveRoom",
+            CoordinatorRequest::ListRooms(_) => "ListRooms",
+            CoordinatorRequest::UpdateRoomStatus(_) => "UpdateRoomStatus",
+            CoordinatorRequest::ReleaseSeat(_) => "ReleaseSeat",
+            CoordinatorRequest::CreateMatch { .. } => "CreateMatch",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CoordinatorResponse {
     // Server related messages
-    ServerJoined,
+    ServerJoined(SessionToken),
     UsernameAlreadyTaken,
+    ServerFull,
     ServerLeft,
+    LoggedIn,
+    Disconnected,
+    SessionResumed {
+        username: Username,
+        room: Option<(RoomName, Process<RoomMsg>)>,
+    },
+    SessionNotFound,
+    SessionExpired,
 
     // Room related messages
     RoomJoined(Process<RoomMsg>),
@@ -37,26 +115,115 @@ pub enum CoordinatorResponse {
     RoomDoesNotExist,
     RoomCreated(Process<RoomMsg>),
     RoomNameAlreadyTaken,
+    RoomKindDoesNotExist,
+    RoomLimitReached,
+    RoomSpectated(Process<RoomMsg>),
     RoomLeft,
+    RoomList(Vec<RoomSummary>),
+    RoomStatusUpdated,
+    SeatReleased,
+    MatchCreated(RoomName, Process<RoomMsg>),
+    // clients that left the server or are in a room already
+    PlayersUnavailable(Vec<Process<ClientMsg>>),
+
+    // Protocol misuse
+    NotJoinedServer,
+    AlreadyInRoom,
+    NotInRoom,
 }
 
 #[derive(Debug)]
 pub(crate) struct ClientInfo {
     tag: Tag,
+    token: SessionToken,
     username: String,
     room: Option<Process<RoomMsg>>,
+    spectating: bool,
+    // logged in to the account of the username, only these games are rated
+    logged_in: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct RoomInfo {
+    process: Process<RoomMsg>,
+    kind: RoomKind,
+    size: RoomSize,
+    // spectators do not count towards size but keep the room alive
+    spectators: usize,
+    max_client: Option<usize>,
+    status: RoomStatus,
 }
 
 pub(crate) type CoordinatorMsg = Request<CoordinatorRequest, CoordinatorResponse>;
 
-pub fn coordinator_process<T: Room>(mailbox: Mailbox<CoordinatorMsg>) -> () {
+/// `services` are handed to the rooms, e.g. to report finished games.
+/// New clients and rooms are refused once `limits` are reached.
+pub fn coordinator_process(
+    (services, limits, room_config): (Services, Limits, RoomsConfig),
+    mailbox: Mailbox<CoordinatorMsg>,
+) {
+    services.log.init();
+    let this = process::this(&mailbox);
+    let registry = crate::room_registry();
     let mut clients = HashMap::<u128, ClientInfo>::new();
-    let mut rooms = HashMap::<RoomName, (Process<RoomMsg>, RoomSize)>::new();
+    // sessions of dropped clients waiting to be resumed
+    let mut disconnected = HashMap::<SessionToken, ClientInfo>::new();
+    let mut rooms = HashMap::<RoomName, RoomInfo>::new();
+    let mut next_match_id = 0u64;
+    // seats in restored rooms waiting for their players to join the server
+    let mut restored = HashMap::<Username, Process<RoomMsg>>::new();
+    // options of a new room, `None` if the kind is unknown
+    let room_options = |kind: &str, options: &RoomOptions| {
+        registry.options(kind, options).map(|mut options| {
+            room_config.apply_defaults(&mut options);
+            options
+        })
+    };
+
+    let snapshots = match services.snapshots.request(SnapshotRequest::Load).unwrap() {
+        SnapshotResponse::Snapshots(snapshots) => snapshots,
+        response => unreachable!("unexpected snapshot response {:?}", response),
+    };
+    for snapshot in snapshots {
+        let room_name = snapshot.name.clone();
+        let members = snapshot.members.clone();
+        let options = snapshot.options.clone();
+        let kind = snapshot.kind.clone();
+        let restored_room = if members.is_empty() {
+            None
+        } else {
+            registry.restore(snapshot, this.clone(), services.clone())
+        };
+        let room_proc = match restored_room {
+            Some(room_proc) => room_proc,
+            None => {
+                services
+                    .snapshots
+                    .request(SnapshotRequest::Remove(room_name))
+                    .unwrap();
+                continue;
+            }
+        };
+        info!(room = %room_name, %kind, members = ?members, "restored room");
+        for username in &members {
+            restored.insert(username.clone(), room_proc.clone());
+        }
+        rooms.insert(
+            room_name,
+            RoomInfo {
+                process: room_proc,
+                max_client: registry.max_client(&kind, &options),
+                kind,
+                size: members.len(),
+                spectators: 0,
+                status: RoomStatus::default(),
+            },
+        );
+    }
 
     let mailbox = mailbox.catch_link_panic();
 
     loop {
-        println!("\nclients: {:?}\nrooms: {:?}", clients, rooms);
         let message = mailbox.receive();
 
         if let Message::Signal(tag) = message {
@@ -72,123 +239,412 @@ pub fn coordinator_process<T: Room>(mailbox: Mailbox<CoordinatorMsg>) -> () {
                     None
                 };
             if let Some(id) = id {
-                clients.remove(&id);
+                let client = clients.remove(&id).unwrap();
+                info!(client_id = id, username = %client.username, "client process died");
             }
         }
 
         if let Message::Normal(request) = message {
             let request = request.unwrap();
             let data = request.data();
+            debug!(
+                client_id = request.sender().id(),
+                username = clients
+                    .get(&request.sender().id())
+                    .map(|client| client.username.as_str()),
+                kind = data.kind(),
+                clients = clients.len(),
+                disconnected = disconnected.len(),
+                rooms = rooms.len(),
+                "coordinator request"
+            );
             match data {
-                CoordinatorRequest::JoinServer(username) => {
-                    if clients.values().find(|c| c.username == *username).is_some() {
+                CoordinatorRequest::JoinServer {
+                    username,
+                    client: client_proc,
+                    logged_in,
+                } => {
+                    if username == BOT_USERNAME
+                        || clients
+                            .values()
+                            .chain(disconnected.values())
+                            .any(|c| c.username == *username)
+                        || (restored.contains_key(username) && !logged_in)
+                    {
                         request.reply(CoordinatorResponse::UsernameAlreadyTaken);
+                    } else if clients.len() + disconnected.len() >= limits.max_clients {
+                        warn!(%username, max_clients = limits.max_clients, "server full");
+                        request.reply(CoordinatorResponse::ServerFull);
                     } else {
+                        let token = rand::random();
+                        let room = restored.remove(username);
+                        if let Some(room_proc) = &room {
+                            let room_name = rooms
+                                .iter()
+                                .find(|(_, room)| room.process == *room_proc)
+                                .map(|(room_name, _)| room_name.clone())
+                                .unwrap();
+                            // let the client know before the room sends it the state
+                            client_proc.send(ClientMsg::RoomAssigned(room_name, room_proc.clone()));
+                            room_proc.send(RoomMsg::Reconnect(
+                                Client::new(username.clone(), client_proc.clone())
+                                    .with_login(*logged_in),
+                            ));
+                        }
                         clients.insert(
                             request.sender().id(),
                             ClientInfo {
                                 tag: request.sender().link(),
+                                token,
                                 username: username.clone(),
-                                room: None,
+                                room,
+                                spectating: false,
+                                logged_in: *logged_in,
                             },
                         );
-                        request.reply(CoordinatorResponse::ServerJoined);
+                        request.reply(CoordinatorResponse::ServerJoined(token));
                     }
                 }
+                CoordinatorRequest::Disconnect => {
+                    if let Some(client) = clients.remove(&request.sender().id()) {
+                        if let Some(room_proc) = &client.room {
+                            room_proc.send(RoomMsg::Disconnect(client.username.clone()));
+                        }
+                        expire_session_later(this.clone(), client.token);
+                        disconnected.insert(client.token, client);
+                    }
+                    request.reply(CoordinatorResponse::Disconnected);
+                }
+                CoordinatorRequest::Resume(token, client_proc) => {
+                    let mut client = match disconnected.remove(token) {
+                        Some(client) => client,
+                        None => {
+                            request.reply(CoordinatorResponse::SessionNotFound);
+                            continue;
+                        }
+                    };
+                    client.tag = request.sender().link();
+                    let room = client.room.as_ref().map(|room_proc| {
+                        room_proc.send(RoomMsg::Reconnect(
+                            Client::new(client.username.clone(), client_proc.clone())
+                                .with_login(client.logged_in),
+                        ));
+                        let room_name = rooms
+                            .iter()
+                            .find(|(_, room)| room.process == *room_proc)
+                            .map(|(room_name, _)| room_name.clone())
+                            .unwrap();
+                        (room_name, room_proc.clone())
+                    });
+                    let username = client.username.clone();
+                    clients.insert(request.sender().id(), client);
+                    request.reply(CoordinatorResponse::SessionResumed { username, room });
+                }
+                CoordinatorRequest::ExpireSession(token) => {
+                    // the session may have been resumed already, and is kept
+                    // for as long as a room holds a seat for it
+                    match disconnected.get(token) {
+                        Some(client) if client.room.is_some() => {
+                            expire_session_later(this.clone(), *token)
+                        }
+                        Some(_) => {
+                            disconnected.remove(token);
+                        }
+                        None => {}
+                    }
+                    request.reply(CoordinatorResponse::SessionExpired);
+                }
+                CoordinatorRequest::Registered => match clients.get_mut(&request.sender().id()) {
+                    Some(client) => {
+                        client.logged_in = true;
+                        request.reply(CoordinatorResponse::LoggedIn);
+                    }
+                    None => request.reply(CoordinatorResponse::NotJoinedServer),
+                },
                 CoordinatorRequest::LeaveServer => {
-                    let mut client = clients.get_mut(&request.sender().id()).unwrap();
-                    if let Some(room_proc) = client.room.as_ref() {
-                        room_proc.send(RoomMsg::Drop(client.username.clone()));
-                        let room_to_remove = if let Some((room_name, (_, room_size))) =
-                            rooms.iter_mut().find(|(_, (p, _))| *p == *room_proc)
-                        {
-                            *room_size -= 1;
-                            if *room_size == 0 {
-                                Some(room_name.to_string())
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        };
-                        client.room = None;
-                        if let Some(room_name) = room_to_remove {
-                            rooms.remove(&room_name);
+                    let client = match clients.get_mut(&request.sender().id()) {
+                        Some(client) => client,
+                        None => {
+                            request.reply(CoordinatorResponse::NotJoinedServer);
+                            continue;
                         }
+                    };
+                    if let Some(room_proc) = client.room.take() {
+                        room_proc.send(RoomMsg::Drop(client.username.clone()));
+                        leave_room(&mut rooms, &room_proc, client.spectating);
                     }
                     clients.remove(&request.sender().id());
                     request.reply(CoordinatorResponse::ServerLeft);
                 }
-                CoordinatorRequest::CreateRoom(room_name, client_proc) => {
-                    if let Some(_) = rooms.get(room_name) {
+                CoordinatorRequest::CreateRoom {
+                    name,
+                    kind,
+                    options,
+                    client: client_proc,
+                } => {
+                    let client = match clients.get_mut(&request.sender().id()) {
+                        Some(client) => client,
+                        None => {
+                            request.reply(CoordinatorResponse::NotJoinedServer);
+                            continue;
+                        }
+                    };
+                    let kind = room_config.kind_or_default(kind);
+                    let options = room_options(&kind, options);
+                    if client.room.is_some() {
+                        request.reply(CoordinatorResponse::AlreadyInRoom);
+                    } else if rooms.contains_key(name) {
                         request.reply(CoordinatorResponse::RoomNameAlreadyTaken);
+                    } else if rooms.len() >= limits.max_rooms {
+                        warn!(room = %name, max_rooms = limits.max_rooms, "room limit reached");
+                        request.reply(CoordinatorResponse::RoomLimitReached);
+                    } else if let Some(options) = options {
+                        let max_client = registry.max_client(&kind, &options);
+                        let room_proc = registry
+                            .spawn(&kind, name.clone(), options, this.clone(), services.clone())
+                            .unwrap();
+                        info!(room = %name, %kind, username = %client.username, "created room");
+                        room_proc.send(RoomMsg::JoinRoom(
+                            Client::new(client.username.clone(), client_proc.clone())
+                                .with_login(client.logged_in),
+                        ));
+                        rooms.insert(
+                            name.clone(),
+                            RoomInfo {
+                                process: room_proc.clone(),
+                                kind,
+                                size: 1,
+                                spectators: 0,
+                                max_client,
+                                status: RoomStatus::default(),
+                            },
+                        );
+                        client.room = Some(room_proc.clone());
+                        request.reply(CoordinatorResponse::RoomCreated(room_proc));
                     } else {
-                        let room_proc = spawn_with(room_name.clone(), room_process::<T>).unwrap();
-                        room_proc.send(RoomMsg::JoinRoom(Client::new(
-                            clients
-                                .get_mut(&request.sender().id())
-                                .unwrap()
-                                .username
-                                .clone(),
-                            client_proc.clone(),
-                        )));
-                        let room_name = room_name.to_string();
-                        rooms.insert(room_name.clone(), (room_proc.clone(), 1));
-                        clients.get_mut(&request.sender().id()).unwrap().room =
-                            Some(room_proc.clone());
-                        request.reply(CoordinatorResponse::RoomCreated(room_proc.clone()));
+                        request.reply(CoordinatorResponse::RoomKindDoesNotExist);
                     }
                 }
                 CoordinatorRequest::JoinRoom(room_name, client_proc) => {
-                    if let Some((room_proc, room_size)) = rooms.get_mut(&room_name.clone()) {
-                        let max_client = T::max_client();
-                        if max_client.is_none() || *room_size < max_client.unwrap() {
-                            *room_size += 1;
-                            room_proc.send(RoomMsg::JoinRoom(Client::new(
-                                clients
-                                    .get_mut(&request.sender().id())
-                                    .unwrap()
-                                    .username
-                                    .clone(),
-                                client_proc.clone(),
-                            )));
-                            clients.get_mut(&request.sender().id()).unwrap().room =
-                                Some(room_proc.clone());
-                            request.reply(CoordinatorResponse::RoomJoined(room_proc.clone()));
+                    let client = match clients.get_mut(&request.sender().id()) {
+                        Some(client) => client,
+                        None => {
+                            request.reply(CoordinatorResponse::NotJoinedServer);
+                            continue;
+                        }
+                    };
+                    if client.room.is_some() {
+                        request.reply(CoordinatorResponse::AlreadyInRoom);
+                    } else if let Some(room) = rooms.get_mut(room_name) {
+                        if !matches!(room.max_client, Some(max) if room.size >= max) {
+                            room.size += 1;
+                            room.process.send(RoomMsg::JoinRoom(
+                                Client::new(client.username.clone(), client_proc.clone())
+                                    .with_login(client.logged_in),
+                            ));
+                            client.room = Some(room.process.clone());
+                            request.reply(CoordinatorResponse::RoomJoined(room.process.clone()));
                         } else {
                             request.reply(CoordinatorResponse::RoomFull);
-                            return;
                         }
                     } else {
                         request.reply(CoordinatorResponse::RoomDoesNotExist);
                     }
                 }
-                CoordinatorRequest::LeaveRoom(client_proc) => {
-                    let mut client = clients.get_mut(&request.sender().id()).unwrap();
-                    let room_proc = client.room.as_ref().unwrap();
-                    room_proc.send(RoomMsg::LeaveRoom(Client::new(
-                        client.username.clone(),
-                        client_proc.clone(),
-                    )));
-                    let room_to_remove = if let Some((room_name, (_, room_size))) =
-                        rooms.iter_mut().find(|(_, (p, _))| *p == *room_proc)
-                    {
-                        *room_size -= 1;
-                        if *room_size == 0 {
-                            Some(room_name.to_string())
-                        } else {
-                            None
+                CoordinatorRequest::SpectateRoom(room_name, client_proc) => {
+                    let client = match clients.get_mut(&request.sender().id()) {
+                        Some(client) => client,
+                        None => {
+                            request.reply(CoordinatorResponse::NotJoinedServer);
+                            continue;
                         }
-                    } else {
-                        None
                     };
-                    client.room = None;
-                    if let Some(room_name) = room_to_remove {
-                        rooms.remove(&room_name);
+                    if client.room.is_some() {
+                        request.reply(CoordinatorResponse::AlreadyInRoom);
+                    } else if let Some(room) = rooms.get_mut(room_name) {
+                        room.spectators += 1;
+                        room.process.send(RoomMsg::Spectate(
+                            Client::new(client.username.clone(), client_proc.clone())
+                                .with_login(client.logged_in),
+                        ));
+                        client.room = Some(room.process.clone());
+                        client.spectating = true;
+                        request.reply(CoordinatorResponse::RoomSpectated(room.process.clone()));
+                    } else {
+                        request.reply(CoordinatorResponse::RoomDoesNotExist);
                     }
+                }
+                CoordinatorRequest::LeaveRoom(client_proc) => {
+                    let client = match clients.get_mut(&request.sender().id()) {
+                        Some(client) => client,
+                        None => {
+                            request.reply(CoordinatorResponse::NotJoinedServer);
+                            continue;
+                        }
+                    };
+                    let room_proc = match client.room.take() {
+                        Some(room_proc) => room_proc,
+                        None => {
+                            request.reply(CoordinatorResponse::NotInRoom);
+                            continue;
+                        }
+                    };
+                    room_proc.send(RoomMsg::LeaveRoom(
+                        Client::new(client.username.clone(), client_proc.clone())
+                            .with_login(client.logged_in),
+                    ));
+                    leave_room(&mut rooms, &room_proc, client.spectating);
+                    client.spectating = false;
                     request.reply(CoordinatorResponse::RoomLeft);
                 }
+                CoordinatorRequest::ListRooms(filter) => {
+                    let mut list: Vec<_> = rooms
+                        .iter()
+                        .map(|(name, room)| RoomSummary {
+                            name: name.clone(),
+                            kind: room.kind.clone(),
+                            size: room.size,
+                            spectators: room.spectators,
+                            max_client: room.max_client,
+                            status: room.status.clone(),
+                        })
+                        .filter(|room| filter.matches(room))
+                        .collect();
+                    list.sort_by(|a, b| a.name.cmp(&b.name));
+                    request.reply(CoordinatorResponse::RoomList(list));
+                }
+                CoordinatorRequest::CreateMatch {
+                    kind,
+                    clients: players,
+                } => {
+                    let unavailable: Vec<_> = players
+                        .iter()
+                        .filter(|player| {
+                            !matches!(clients.get(&player.id()), Some(client) if client.room.is_none())
+                        })
+                        .cloned()
+                        .collect();
+                    if !unavailable.is_empty() {
+                        request.reply(CoordinatorResponse::PlayersUnavailable(unavailable));
+                        continue;
+                    }
+                    if rooms.len() >= limits.max_rooms {
+                        warn!(max_rooms = limits.max_rooms, "room limit reached");
+                        request.reply(CoordinatorResponse::RoomLimitReached);
+                        continue;
+                    }
+                    let options = match room_options(kind, &RoomOptions::new()) {
+                        Some(options) => options,
+                        None => {
+                            request.reply(CoordinatorResponse::RoomKindDoesNotExist);
+                            continue;
+                        }
+                    };
+                    let name = loop {
+                        next_match_id += 1;
+                        let name = format!("match-{}", next_match_id);
+                        if !rooms.contains_key(&name) {
+                            break name;
+                        }
+                    };
+                    let max_client = registry.max_client(kind, &options);
+                    let room_proc = registry
+                        .spawn(kind, name.clone(), options, this.clone(), services.clone())
+                        .unwrap();
+                    for player in players {
+                        let client = clients.get_mut(&player.id()).unwrap();
+                        room_proc.send(RoomMsg::JoinRoom(
+                            Client::new(client.username.clone(), player.clone())
+                                .with_login(client.logged_in),
+                        ));
+                        client.room = Some(room_proc.clone());
+                    }
+                    rooms.insert(
+                        name.clone(),
+                        RoomInfo {
+                            process: room_proc.clone(),
+                            kind: kind.clone(),
+                            size: players.len(),
+                            spectators: 0,
+                            max_client,
+                            status: RoomStatus::default(),
+                        },
+                    );
+                    info!(room = %name, %kind, "created match");
+                    request.reply(CoordinatorResponse::MatchCreated(name, room_proc));
+                }
+                CoordinatorRequest::ReleaseSeat(username) => {
+                    let sender = request.sender().id();
+                    let client =
+                        clients
+                            .values_mut()
+                            .chain(disconnected.values_mut())
+                            .find(|client| {
+                                client.username == *username
+                                    && matches!(&client.room, Some(room) if room.id() == sender)
+                            });
+                    if let Some(client) = client {
+                        let room_proc = client.room.take().unwrap();
+                        leave_room(&mut rooms, &room_proc, client.spectating);
+                        client.spectating = false;
+                    } else if matches!(restored.get(username), Some(room) if room.id() == sender) {
+                        // the player did not come back after the restart
+                        let room_proc = restored.remove(username).unwrap();
+                        leave_room(&mut rooms, &room_proc, false);
+                    }
+                    request.reply(CoordinatorResponse::SeatReleased);
+                }
+                CoordinatorRequest::UpdateRoomStatus(status) => {
+                    // the room may already be forgotten if everyone left
+                    let sender = request.sender().id();
+                    if let Some(room) = rooms.values_mut().find(|room| room.process.id() == sender)
+                    {
+                        room.status = status.clone();
+                    }
+                    request.reply(CoordinatorResponse::RoomStatusUpdated);
+                }
             }
         }
     }
 }
+
+/// Send `ExpireSession` to the coordinator once the grace period is over
+fn expire_session_later(coordinator: Process<CoordinatorMsg>, token: SessionToken) {
+    process::spawn_with(
+        (coordinator, token),
+        |(coordinator, token), _: Mailbox<()>| {
+            lunatic::sleep(SESSION_GRACE_PERIOD.as_millis() as u64);
+            coordinator
+                .request(CoordinatorRequest::ExpireSession(token))
+                .unwrap();
+        },
+    )
+    .unwrap();
+}
+
+/// Decrease the size of the room and forget it once it is empty
+fn leave_room(
+    rooms: &mut HashMap<RoomName, RoomInfo>,
+    room_proc: &Process<RoomMsg>,
+    spectating: bool,
+) {
+    let room_to_remove = rooms
+        .iter_mut()
+        .find(|(_, room)| room.process == *room_proc)
+        .and_then(|(room_name, room)| {
+            if spectating {
+                room.spectators -= 1;
+            } else {
+                room.size -= 1;
+            }
+            if room.size == 0 && room.spectators == 0 {
+                Some(room_name.clone())
+            } else {
+                None
+            }
+        });
+    if let Some(room_name) = room_to_remove {
+        info!(room = %room_name, "closed room");
+        rooms.remove(&room_name);
+    }
+}
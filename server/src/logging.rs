@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+/// Filter directives of the log, a level like `info` optionally followed by
+/// levels of single modules, e.g. `info,server::coordinator=debug`. Every
+/// process runs in its own instance with its own subscriber, so the filter is
+/// handed to each process that logs and installed with `init`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogFilter(String);
+
+impl LogFilter {
+    pub fn new(directives: &str) -> Result<Self, String> {
+        EnvFilter::try_new(directives).map_err(|err| err.to_string())?;
+        Ok(Self(directives.to_string()))
+    }
+
+    /// Log JSON lines to stdout from the calling process. Does nothing if the
+    /// process already logs.
+    pub fn init(&self) {
+        let _ = tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(EnvFilter::new(&self.0))
+            .try_init();
+    }
+}
@@ -1,82 +1,516 @@
+mod auth;
+mod bot;
 mod client;
+mod config;
 mod coordinator;
+mod logging;
+mod matchmaker;
+mod rating;
+mod registry;
+mod replay;
 mod room;
+mod snapshot;
+mod store;
+mod tls;
+mod transport;
 
+use auth::{auth_process, AuthMsg};
+use bot::{bot_process, BOT_USERNAME};
 use client::client_process;
-use coordinator::coordinator_process;
-use lunatic::{net, process};
-use room::Room;
-use shared::message::{GameAction, GameState};
+use config::Config;
+use coordinator::{coordinator_process, CoordinatorMsg};
+use lunatic::{
+    net::TcpListener,
+    process::{self, Process},
+    Mailbox,
+};
+use matchmaker::{matchmaker_process, MatchmakerMsg};
+use rating::rating_process;
+use registry::RoomRegistry;
+use replay::replay_process;
+use room::{Room, Services};
+use serde::{de::DeserializeOwned, Serialize};
+use shared::{
+    ai::Difficulty,
+    message::{
+        GameAction, GameState, MessageFromServer, RoomName, RoomOptions, RoomStatus, TimeControl,
+        Username, DEFAULT_ROOM_KIND,
+    },
+    replay::Replay,
+    transport::{FramedTransport, Transport, WebSocketTransport},
+};
+use snapshot::snapshot_process;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tls::{TlsIdentity, TlsTransport};
+use tracing::{debug, info};
+use transport::ClientStream;
+
+/// Largest width or height a client can ask for
+const MAX_BOARD_SIZE: usize = 32;
+
+/// How often the clocks of timed games are checked
+const CLOCK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Seconds the seat of a disconnected player is kept if the room options do
+/// not say, the coordinator fills in the configured default
+const DEFAULT_RESERVATION_TIMEOUT: u64 = 60;
+
+/// Longest reservation in seconds a room can ask for
+const MAX_RESERVATION_TIMEOUT: u64 = 24 * 60 * 60;
+
+/// Most seconds a time control option can give a player
+const MAX_TIME_CONTROL: u64 = 24 * 60 * 60;
 
 struct GameRoom {
     room_name: String,
     state: GameState,
+    // difficulty of the bot taking the second seat, if the room has one
+    bot: Option<Difficulty>,
+    reservation_timeout: Duration,
+    time_control: Option<TimeControl>,
+    // when the clock of the current player was last updated
+    clock_updated: Instant,
+    // moves of the current game and when it started, for its replay
+    moves: Vec<(usize, usize)>,
+    started_at: u64,
+    // players logged in to the account of their username
+    accounts: HashSet<Username>,
+}
+
+impl GameRoom {
+    fn bot_option(options: &RoomOptions) -> Option<Difficulty> {
+        options.get("bot").and_then(|value| value.parse().ok())
+    }
+
+    /// `time_per_move=<seconds>`, or `clock=<seconds>` with an optional
+    /// `increment=<seconds>`, each up to a day
+    fn time_control_option(options: &RoomOptions) -> Option<TimeControl> {
+        let option = |key: &str| {
+            options
+                .get(key)
+                .and_then(|value| value.parse().ok())
+                .filter(|seconds| (1..=MAX_TIME_CONTROL).contains(seconds))
+        };
+        if let Some(seconds) = option("time_per_move") {
+            Some(TimeControl::PerMove { seconds })
+        } else {
+            option("clock").map(|seconds| TimeControl::Clock {
+                seconds,
+                increment: option("increment").unwrap_or(0),
+            })
+        }
+    }
+
+    /// Start a new game with fresh clocks
+    fn restart(&mut self) {
+        self.state.reset_game();
+        if let Some(time_control) = &self.time_control {
+            self.state.start_clocks(time_control);
+        }
+        self.clock_updated = Instant::now();
+        self.moves.clear();
+        self.started_at = unix_time();
+    }
+
+    /// Charge the time since the last update to the player whose turn it is.
+    /// Returns true if the player ran out of time.
+    fn update_clock(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now - self.clock_updated;
+        self.clock_updated = now;
+        self.state.run_clock(elapsed.as_millis() as u64)
+    }
+
+    /// Save the replay of the game that just ended and let the rating
+    /// service know the result, only games between two logged-in accounts
+    /// are rated
+    fn finish_game(&self, ctx: &room::Context<GameState>) {
+        let id = ctx.save_replay(Replay {
+            id: 0,
+            room_name: self.room_name.clone(),
+            players: self.state.players.clone(),
+            width: self.state.width(),
+            height: self.state.height(),
+            win_length: self.state.win_length(),
+            started_at: self.started_at,
+            finished_at: unix_time(),
+            moves: self.moves.clone(),
+            winner: self.state.winner,
+            timed_out: self.state.timed_out,
+        });
+        info!(room = %self.room_name, replay = id, "saved replay");
+        if let [first, second] = &self.state.players[..] {
+            if self.accounts.contains(first) && self.accounts.contains(second) {
+                let winner = self.state.winner.map(|player| player as usize);
+                ctx.report_game([first.clone(), second.clone()], winner);
+            }
+        }
+    }
+
+    fn spawn_bot(&self, difficulty: Difficulty, ctx: &mut room::Context<GameState>) {
+        let bot =
+            process::spawn_with((ctx.room(), difficulty, ctx.log_filter()), bot_process).unwrap();
+        ctx.insert(room::Client::new(BOT_USERNAME.to_string(), bot));
+    }
+
+    fn remove_player(&mut self, username: &Username, ctx: &mut room::Context<GameState>) {
+        self.state.players.retain(|p| p != username);
+        self.state.disconnected.retain(|p| p != username);
+        self.accounts.remove(username);
+        // the bot does not stay in a room without human players
+        if self.state.players.iter().all(|p| p == BOT_USERNAME) {
+            self.state.players.clear();
+            ctx.remove(&BOT_USERNAME.to_string());
+        }
+        self.state.started = false;
+        self.restart();
+        ctx.broadcast(&self.state);
+    }
 }
 
 impl Room for GameRoom {
-    fn new(room_name: String) -> Self {
+    type State = GameState;
+    type Action = GameAction;
+
+    fn new(room_name: RoomName, options: &RoomOptions) -> Self {
+        let option = |key: &str, default: usize| {
+            options
+                .get(key)
+                .and_then(|value| value.parse().ok())
+                .filter(|value| (1..=MAX_BOARD_SIZE).contains(value))
+                .unwrap_or(default)
+        };
+        let width = option("width", 3);
+        let height = option("height", 3);
+        let win_length = option("win_length", width.min(height)).min(width.max(height));
+        let reservation_timeout = options
+            .get("reservation_timeout")
+            .and_then(|value| value.parse().ok())
+            .filter(|seconds| (1..=MAX_RESERVATION_TIMEOUT).contains(seconds))
+            .unwrap_or(DEFAULT_RESERVATION_TIMEOUT);
         Self {
             room_name,
-            state: GameState::default(),
+            state: GameState::new(width, height, win_length),
+            bot: Self::bot_option(options),
+            reservation_timeout: Duration::from_secs(reservation_timeout),
+            time_control: Self::time_control_option(options),
+            clock_updated: Instant::now(),
+            moves: Vec::new(),
+            started_at: unix_time(),
+            accounts: HashSet::new(),
         }
     }
 
-    fn on_join(&mut self, client: room::Client, ctx: &room::Context) {
-        println!("Client {} joined room {}", client.username, self.room_name);
+    fn restore(room_name: RoomName, options: &RoomOptions, snapshot: &[u8]) -> Option<Self> {
+        let (state, moves, started_at) = bincode::deserialize(snapshot).ok()?;
+        Some(Self {
+            state,
+            moves,
+            started_at,
+            ..Self::new(room_name, options)
+        })
+    }
+
+    fn on_restore(&mut self, ctx: &mut room::Context<GameState>) {
+        info!(room = %self.room_name, "room restored");
+        // the game waits for every human player to come back
+        self.state.disconnected = self
+            .state
+            .players
+            .iter()
+            .filter(|p| *p != BOT_USERNAME)
+            .cloned()
+            .collect();
+        if let Some(difficulty) = self.bot {
+            if self.state.players.iter().any(|p| p == BOT_USERNAME) {
+                self.spawn_bot(difficulty, ctx);
+            }
+        }
+        self.clock_updated = Instant::now();
+        ctx.broadcast(&self.state);
+    }
+
+    fn on_join(&mut self, client: room::Client, ctx: &mut room::Context<GameState>) {
+        info!(room = %self.room_name, username = %client.username, "joined room");
         self.state.players.push(client.username.clone());
+        if client.logged_in {
+            self.accounts.insert(client.username.clone());
+        }
+        if let Some(difficulty) = self.bot {
+            self.spawn_bot(difficulty, ctx);
+            self.state.players.push(BOT_USERNAME.to_string());
+        }
         if self.state.players.len() == 2 {
             self.state.started = true;
+            self.restart();
         }
         ctx.broadcast(&self.state);
     }
 
-    fn on_leave(&mut self, client: room::Client, ctx: &room::Context) {
-        println!("Client {} left room {}", client.username, self.room_name);
-        self.state.players.retain(|p| *p != client.username);
-        self.state.started = false;
-        self.state.reset_game();
-        ctx.broadcast(&self.state);
+    fn on_spectate(&mut self, client: room::Client, _ctx: &mut room::Context<GameState>) {
+        info!(room = %self.room_name, username = %client.username, "spectating room");
     }
 
-    fn on_drop(&mut self, client_username: shared::message::Username, ctx: &room::Context) {
-        println!(
-            "Client {} dropped from room {}",
-            client_username, self.room_name
-        );
-        self.state.players.retain(|p| *p != client_username);
-        self.state.started = false;
-        self.state.reset_game();
+    fn on_leave(&mut self, client: room::Client, ctx: &mut room::Context<GameState>) {
+        info!(room = %self.room_name, username = %client.username, "left room");
+        self.remove_player(&client.username, ctx);
+    }
+
+    fn on_drop(&mut self, client_username: Username, ctx: &mut room::Context<GameState>) {
+        info!(room = %self.room_name, username = %client_username, "dropped from room");
+        self.remove_player(&client_username, ctx);
+    }
+
+    fn on_disconnect(&mut self, client_username: Username, ctx: &mut room::Context<GameState>) {
+        info!(room = %self.room_name, username = %client_username, "disconnected from room");
+        if self.state.players.contains(&client_username) {
+            self.state.disconnected.push(client_username);
+            ctx.broadcast(&self.state);
+        }
+    }
+
+    fn on_reconnect(&mut self, client: room::Client, ctx: &mut room::Context<GameState>) {
+        info!(room = %self.room_name, username = %client.username, "reconnected to room");
+        self.state.disconnected.retain(|p| *p != client.username);
+        if client.logged_in && self.state.players.contains(&client.username) {
+            self.accounts.insert(client.username.clone());
+        } else {
+            self.accounts.remove(&client.username);
+        }
         ctx.broadcast(&self.state);
     }
 
-    fn on_msg(&mut self, client: room::Client, msg: GameAction, ctx: &room::Context) {
-        println!(
-            "Client {} messaged {:?} in room {}",
-            client.username, msg, self.room_name
+    fn on_msg(
+        &mut self,
+        client: room::Client,
+        msg: GameAction,
+        ctx: &mut room::Context<GameState>,
+    ) {
+        debug!(
+            room = %self.room_name,
+            username = %client.username,
+            action = ?msg,
+            "game action"
         );
-        match msg {
-            GameAction::PlaceNode(x, y) => {
-                self.state.place_node(x, y, &client.username);
-                self.state.turn = (self.state.turn == 0) as u8;
-            }
-            GameAction::Restart => {
-                if self.state.winner.is_some() || self.state.is_gameover() {
-                    self.state.reset_game();
+        // a move that comes in after the time ran out is too late
+        if self.update_clock() {
+            self.finish_game(ctx);
+            ctx.broadcast(&self.state);
+        }
+        let result = match msg {
+            GameAction::PlaceNode(x, y) => self
+                .state
+                .validate_place_node(x, y, &client.username)
+                .map(|_| {
+                    self.state.place_node(x, y, &client.username);
+                    self.moves.push((x, y));
+                    if let Some(time_control) = &self.time_control {
+                        let time_left = &mut self.state.time_left[self.state.turn as usize];
+                        *time_left = time_control.after_move(*time_left);
+                    }
+                    self.state.turn = (self.state.turn == 0) as u8;
+                }),
+            GameAction::Restart => self
+                .state
+                .validate_restart(&client.username)
+                .map(|_| self.restart()),
+        };
+        match result {
+            Ok(()) => {
+                if matches!(msg, GameAction::PlaceNode(..)) && self.state.is_gameover() {
+                    self.finish_game(ctx);
                 }
+                ctx.broadcast(&self.state);
+            }
+            Err(rejection) => {
+                debug!(
+                    room = %self.room_name,
+                    username = %client.username,
+                    %rejection,
+                    "rejected game action"
+                );
+                ctx.send(
+                    &client.username,
+                    MessageFromServer::ActionRejected(rejection),
+                );
             }
         }
-        ctx.broadcast(&self.state);
     }
 
-    fn max_client() -> Option<usize> {
-        Some(2)
+    fn status(&self) -> RoomStatus {
+        RoomStatus {
+            started: self.state.started,
+            players: self.state.players.clone(),
+        }
+    }
+
+    fn on_update(&mut self, _delta_time: Duration, ctx: &mut room::Context<GameState>) {
+        let seconds_left = |state: &GameState| {
+            state
+                .time_left
+                .get(state.turn as usize)
+                .map(|millis| millis.div_ceil(1000))
+        };
+        let before = seconds_left(&self.state);
+        let timed_out = self.update_clock();
+        if timed_out {
+            self.finish_game(ctx);
+        }
+        // clients count down on their own, keep them in sync every second
+        if timed_out || seconds_left(&self.state) != before {
+            ctx.broadcast(&self.state);
+        }
+    }
+
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        // the replay of the game in progress is restored along with it
+        bincode::serialize(&(&self.state, &self.moves, self.started_at)).ok()
+    }
+
+    fn update_interval(&self) -> Option<Duration> {
+        self.time_control.map(|_| CLOCK_INTERVAL)
+    }
+
+    fn reservation_timeout(&self) -> Option<Duration> {
+        Some(self.reservation_timeout)
+    }
+
+    fn max_client(options: &RoomOptions) -> Option<usize> {
+        // the bot takes the second seat
+        match Self::bot_option(options) {
+            Some(_) => Some(1),
+            None => Some(2),
+        }
     }
 }
 
+/// Seconds since the unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Room kinds that clients can create, used by the coordinator
+pub(crate) fn room_registry() -> RoomRegistry {
+    let mut registry = RoomRegistry::new();
+    registry
+        .register::<GameRoom>(DEFAULT_ROOM_KIND, RoomOptions::new())
+        .register::<GameRoom>(
+            "tic-tac-toe-4x4",
+            room_options(&[("width", "4"), ("height", "4"), ("win_length", "4")]),
+        )
+        .register::<GameRoom>(
+            "gomoku",
+            room_options(&[("width", "15"), ("height", "15"), ("win_length", "5")]),
+        );
+    registry
+}
+
+fn room_options(options: &[(&str, &str)]) -> RoomOptions {
+    options
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
 fn main() {
-    let coordinator = process::spawn(coordinator_process::<GameRoom>).unwrap();
-    let listener = net::TcpListener::bind("127.0.0.1:1337").unwrap();
+    let config = Config::load().unwrap_or_else(|err| exit_with_error(&err));
+    let network = config.network.clone();
+    let tls_identity = network.tls_address.as_ref().map(|_| {
+        TlsIdentity::load(&network.tls_cert, &network.tls_key)
+            .unwrap_or_else(|err| exit_with_error(&err))
+    });
+    let log = config.log.filter().expect("the log filter is validated");
+    log.init();
+    info!(
+        tcp = %network.tcp_address,
+        websocket = %network.websocket_address,
+        tls = network.tls_address.as_deref(),
+        "starting server"
+    );
+
+    let storage = config.storage;
+    let services = Services {
+        rating: process::spawn_with(storage.ratings, rating_process).unwrap(),
+        replays: process::spawn_with((storage.replays, log.clone()), replay_process).unwrap(),
+        snapshots: process::spawn_with(storage.snapshots, snapshot_process).unwrap(),
+        log,
+    };
+    let coordinator = process::spawn_with(
+        (services.clone(), config.limits, config.rooms.clone()),
+        coordinator_process,
+    )
+    .unwrap();
+    let matchmaker =
+        process::spawn_with((coordinator.clone(), config.rooms), matchmaker_process).unwrap();
+    let auth = process::spawn_with(storage.accounts, auth_process).unwrap();
+    let handles = (coordinator, matchmaker, auth, services);
+    let read_timeout = network.read_timeout();
+
+    process::spawn_with(
+        (
+            handles.clone(),
+            network.websocket_address.clone(),
+            read_timeout,
+        ),
+        |(handles, address, read_timeout), _: Mailbox<()>| {
+            listen(&address, read_timeout, WebSocketTransport, handles)
+        },
+    )
+    .unwrap();
+    if let (Some(address), Some(identity)) = (network.tls_address.clone(), tls_identity) {
+        process::spawn_with(
+            (handles.clone(), address, read_timeout, identity),
+            |(handles, address, read_timeout, identity), _: Mailbox<()>| {
+                let transport = |stream| TlsTransport::new(stream, identity.clone());
+                listen(&address, read_timeout, transport, handles)
+            },
+        )
+        .unwrap();
+    }
+    listen(&network.tcp_address, read_timeout, FramedTransport, handles);
+}
+
+fn exit_with_error(err: &str) -> ! {
+    eprintln!("invalid configuration: {}", err);
+    std::process::exit(1)
+}
+
+/// Processes every client process talks to
+type Handles = (
+    Process<CoordinatorMsg>,
+    Process<MatchmakerMsg>,
+    Process<AuthMsg>,
+    Services,
+);
+
+/// Accept connections on `address` and spawn a client process for each,
+/// clients that stay silent for `read_timeout` are dropped
+fn listen<T, F>(address: &str, read_timeout: Duration, transport: F, handles: Handles)
+where
+    F: Fn(ClientStream) -> T,
+    T: Transport + Serialize + DeserializeOwned + Debug,
+    T::Reader: Serialize + DeserializeOwned,
+{
+    let (coordinator, matchmaker, auth, services) = handles;
+    services.log.init();
+    let listener =
+        TcpListener::bind(address).unwrap_or_else(|_| panic!("cannot listen on {}", address));
+    info!(address, "listening");
     while let Ok((tcp_stream, _peer)) = listener.accept() {
-        process::spawn_with((tcp_stream, coordinator.clone()), client_process).unwrap();
+        process::spawn_with(
+            (
+                transport(ClientStream::new(tcp_stream, read_timeout)),
+                coordinator.clone(),
+                matchmaker.clone(),
+                auth.clone(),
+                services.clone(),
+            ),
+            client_process::<T>,
+        )
+        .unwrap();
     }
 }
@@ -0,0 +1,147 @@
+use lunatic::{process::Process, Mailbox, ReceiveError};
+use serde::{Deserialize, Serialize};
+use shared::message::{MessageFromServer, RoomKind, RoomOptions};
+use std::time::{Duration, Instant};
+
+use crate::client::ClientMsg;
+use crate::config::RoomsConfig;
+use crate::coordinator::{CoordinatorMsg, CoordinatorRequest, CoordinatorResponse};
+use crate::room::timeout_millis;
+
+/// How long a player waits for an opponent before giving up
+const MATCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize)]
+pub enum MatchmakerMsg {
+    FindMatch(RoomKind, Process<ClientMsg>),
+    CancelMatch(Process<ClientMsg>),
+}
+
+struct Ticket {
+    kind: RoomKind,
+    client: Process<ClientMsg>,
+    queued_at: Instant,
+}
+
+/// Queue of players looking for an opponent. Two players waiting for the
+/// same room kind are put into a new room created by the coordinator.
+/// A ticket without a kind waits for the default kind of `room_config`.
+pub fn matchmaker_process(
+    (coordinator, room_config): (Process<CoordinatorMsg>, RoomsConfig),
+    mailbox: Mailbox<MatchmakerMsg>,
+) {
+    let registry = crate::room_registry();
+    let mut queue = Vec::<Ticket>::new();
+
+    loop {
+        let now = Instant::now();
+        queue.retain(|ticket| {
+            let expired = now - ticket.queued_at >= MATCH_TIMEOUT;
+            if expired {
+                notify(&ticket.client, MessageFromServer::MatchTimedOut);
+            }
+            !expired
+        });
+
+        // wake up in time for the oldest ticket to expire
+        let oldest = queue.iter().map(|ticket| ticket.queued_at).min();
+        let message = match oldest {
+            Some(queued_at) => {
+                let timeout = timeout_millis(queued_at + MATCH_TIMEOUT - now);
+                match mailbox.receive_with_timeout(timeout) {
+                    Ok(message) => message,
+                    Err(ReceiveError::Timeout) => continue,
+                    Err(_) => break,
+                }
+            }
+            None => match mailbox.receive() {
+                Ok(message) => message,
+                Err(_) => break,
+            },
+        };
+
+        match message {
+            MatchmakerMsg::FindMatch(kind, client) => {
+                let kind = room_config.kind_or_default(&kind);
+                if registry.options(&kind, &RoomOptions::new()).is_none() {
+                    notify(&client, MessageFromServer::RoomKindDoesNotExist);
+                    continue;
+                }
+                queue.retain(|ticket| ticket.client != client);
+                notify(&client, MessageFromServer::MatchQueued);
+                queue.push(Ticket {
+                    kind,
+                    client,
+                    queued_at: Instant::now(),
+                });
+                while let Some((first, second)) = find_pair(&queue) {
+                    // remove the later ticket first so that `first` stays valid
+                    let second = queue.remove(second);
+                    let first = queue.remove(first);
+                    create_match(&coordinator, &mut queue, first, second);
+                }
+            }
+            MatchmakerMsg::CancelMatch(client) => {
+                let len = queue.len();
+                queue.retain(|ticket| ticket.client != client);
+                // the client may have been matched already
+                if queue.len() < len {
+                    notify(&client, MessageFromServer::MatchCancelled);
+                }
+            }
+        }
+    }
+}
+
+/// Oldest two tickets waiting for the same room kind
+fn find_pair(queue: &[Ticket]) -> Option<(usize, usize)> {
+    queue.iter().enumerate().find_map(|(i, first)| {
+        queue[i + 1..]
+            .iter()
+            .position(|second| second.kind == first.kind)
+            .map(|j| (i, i + 1 + j))
+    })
+}
+
+fn create_match(
+    coordinator: &Process<CoordinatorMsg>,
+    queue: &mut Vec<Ticket>,
+    first: Ticket,
+    second: Ticket,
+) {
+    let response = coordinator
+        .request(CoordinatorRequest::CreateMatch {
+            kind: first.kind.clone(),
+            clients: vec![first.client.clone(), second.client.clone()],
+        })
+        .unwrap();
+    match response {
+        CoordinatorResponse::MatchCreated(room_name, room_proc) => {
+            for ticket in [first, second].iter() {
+                ticket.client.send(ClientMsg::RoomAssigned(
+                    room_name.clone(),
+                    room_proc.clone(),
+                ));
+            }
+        }
+        // players that left the server or joined a room in the meantime lose
+        // their ticket, the others keep their place in the queue
+        CoordinatorResponse::PlayersUnavailable(unavailable) => {
+            for ticket in [second, first] {
+                if !unavailable.contains(&ticket.client) {
+                    queue.insert(0, ticket);
+                }
+            }
+        }
+        CoordinatorResponse::RoomLimitReached => {
+            for ticket in [first, second].iter() {
+                notify(&ticket.client, MessageFromServer::RoomLimitReached);
+            }
+        }
+        response => unreachable!("unexpected coordinator response {:?}", response),
+    }
+}
+
+fn notify(client: &Process<ClientMsg>, msg: MessageFromServer) {
+    client.send(ClientMsg::MatchmakerMessage(msg));
+}
@@ -0,0 +1,81 @@
+use lunatic::{Mailbox, Request};
+use serde::{Deserialize, Serialize};
+use shared::{
+    message::Username,
+    rating::{GameResult, PlayerStats},
+};
+use std::collections::HashMap;
+
+use crate::store;
+
+/// Most players returned for a single leaderboard request
+const MAX_LEADERBOARD_SIZE: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RatingRequest {
+    // sent by rooms, `winner` is the index into `players` or `None` for a draw
+    ReportGame {
+        players: [Username; 2],
+        winner: Option<usize>,
+    }, // -> GameRecorded
+    GetLeaderboard(usize),    // -> Leaderboard
+    GetPlayerStats(Username), // -> PlayerStats
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RatingResponse {
+    GameRecorded,
+    Leaderboard(Vec<PlayerStats>),
+    PlayerStats(Option<PlayerStats>),
+}
+
+pub(crate) type RatingMsg = Request<RatingRequest, RatingResponse>;
+
+/// Keeps the Elo rating and record of every player that finished a game,
+/// saved to the file at `path` after each game
+pub fn rating_process(path: String, mailbox: Mailbox<RatingMsg>) {
+    let mut players: HashMap<Username, PlayerStats> = store::load(&path);
+
+    while let Ok(request) = mailbox.receive() {
+        let response = match request.data() {
+            RatingRequest::ReportGame {
+                players: usernames,
+                winner,
+            } => {
+                // both ratings change based on the ratings before the game
+                let ratings = usernames.clone().map(|username| {
+                    players
+                        .entry(username.clone())
+                        .or_insert_with(|| PlayerStats::new(username))
+                        .rating
+                });
+                for (i, username) in usernames.iter().enumerate() {
+                    let result = match winner {
+                        None => GameResult::Draw,
+                        Some(winner) if *winner == i => GameResult::Win,
+                        Some(_) => GameResult::Loss,
+                    };
+                    if let Some(stats) = players.get_mut(username) {
+                        stats.record(ratings[1 - i], result);
+                    }
+                }
+                store::save(&path, &players);
+                RatingResponse::GameRecorded
+            }
+            RatingRequest::GetLeaderboard(top_n) => {
+                let mut leaderboard: Vec<_> = players.values().cloned().collect();
+                leaderboard.sort_by(|a, b| {
+                    b.rating
+                        .total_cmp(&a.rating)
+                        .then_with(|| a.username.cmp(&b.username))
+                });
+                leaderboard.truncate((*top_n).min(MAX_LEADERBOARD_SIZE));
+                RatingResponse::Leaderboard(leaderboard)
+            }
+            RatingRequest::GetPlayerStats(username) => {
+                RatingResponse::PlayerStats(players.get(username).cloned())
+            }
+        };
+        request.reply(response);
+    }
+}
@@ -0,0 +1,121 @@
+use lunatic::process::{spawn_with, Process};
+use shared::message::{RoomKind, RoomName, RoomOptions};
+use std::collections::HashMap;
+
+use crate::coordinator::CoordinatorMsg;
+use crate::room::{room_process, Room, RoomMsg, Services};
+use crate::snapshot::RoomSnapshot;
+
+type SpawnRoom = fn(
+    RoomName,
+    RoomKind,
+    RoomOptions,
+    Process<CoordinatorMsg>,
+    Services,
+    Option<RoomSnapshot>,
+) -> Process<RoomMsg>;
+type MaxClient = fn(&RoomOptions) -> Option<usize>;
+
+struct RoomType {
+    spawn: SpawnRoom,
+    max_client: MaxClient,
+    default_options: RoomOptions,
+}
+
+/// Room types that can be created by clients, keyed by their kind.
+/// The same `Room` implementation can be registered several times under
+/// different kinds with different default options.
+#[derive(Default)]
+pub struct RoomRegistry {
+    kinds: HashMap<RoomKind, RoomType>,
+}
+
+impl RoomRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<T: Room>(&mut self, kind: &str, default_options: RoomOptions) -> &mut Self {
+        self.kinds.insert(
+            kind.to_string(),
+            RoomType {
+                spawn: spawn_room::<T>,
+                max_client: T::max_client,
+                default_options,
+            },
+        );
+        self
+    }
+
+    /// Merge the client supplied options over the defaults of the kind
+    pub fn options(&self, kind: &str, options: &RoomOptions) -> Option<RoomOptions> {
+        self.kinds.get(kind).map(|room_type| {
+            let mut merged = room_type.default_options.clone();
+            merged.extend(options.iter().map(|(k, v)| (k.clone(), v.clone())));
+            merged
+        })
+    }
+
+    pub fn max_client(&self, kind: &str, options: &RoomOptions) -> Option<usize> {
+        self.kinds
+            .get(kind)
+            .and_then(|room_type| (room_type.max_client)(options))
+    }
+
+    /// Spawn a room of the given kind, returns `None` if the kind is unknown.
+    /// `options` are expected to be merged with `RoomRegistry::options` already.
+    pub fn spawn(
+        &self,
+        kind: &str,
+        room_name: RoomName,
+        options: RoomOptions,
+        coordinator: Process<CoordinatorMsg>,
+        services: Services,
+    ) -> Option<Process<RoomMsg>> {
+        self.kinds.get(kind).map(|room_type| {
+            (room_type.spawn)(
+                room_name,
+                kind.to_string(),
+                options,
+                coordinator,
+                services,
+                None,
+            )
+        })
+    }
+
+    /// Spawn a room from the snapshot it left behind before a restart,
+    /// returns `None` if its kind is no longer registered
+    pub fn restore(
+        &self,
+        snapshot: RoomSnapshot,
+        coordinator: Process<CoordinatorMsg>,
+        services: Services,
+    ) -> Option<Process<RoomMsg>> {
+        self.kinds.get(&snapshot.kind).map(|room_type| {
+            (room_type.spawn)(
+                snapshot.name.clone(),
+                snapshot.kind.clone(),
+                snapshot.options.clone(),
+                coordinator,
+                services,
+                Some(snapshot),
+            )
+        })
+    }
+}
+
+fn spawn_room<T: Room>(
+    room_name: RoomName,
+    kind: RoomKind,
+    options: RoomOptions,
+    coordinator: Process<CoordinatorMsg>,
+    services: Services,
+    snapshot: Option<RoomSnapshot>,
+) -> Process<RoomMsg> {
+    spawn_with(
+        (room_name, kind, options, coordinator, services, snapshot),
+        room_process::<T>,
+    )
+    .unwrap()
+}
@@ -0,0 +1,100 @@
+use lunatic::{Mailbox, Request};
+use serde::{Deserialize, Serialize};
+use shared::{
+    message::Username,
+    replay::{decode_replay, encode_replay, Replay, ReplayId, ReplaySummary},
+};
+use std::fs;
+use tracing::warn;
+
+use crate::logging::LogFilter;
+use crate::store;
+
+/// Most replays returned for a single listing, newest first
+const MAX_LISTED_REPLAYS: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ReplayRequest {
+    // sent by rooms, the id of the replay is assigned here
+    Save(Replay),   // -> Saved
+    Get(ReplayId),  // -> Replay
+    List(Username), // -> ReplayList
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ReplayResponse {
+    Saved(ReplayId),
+    Replay(Option<Replay>),
+    ReplayList(Vec<ReplaySummary>),
+}
+
+pub(crate) type ReplayMsg = Request<ReplayRequest, ReplayResponse>;
+
+/// Writes every finished game to its own file in `dir`. Only the summaries
+/// are kept in memory, replays are read back from disk when requested.
+pub fn replay_process((dir, log): (String, LogFilter), mailbox: Mailbox<ReplayMsg>) {
+    log.init();
+    fs::create_dir_all(&dir).expect("failed to create replay directory");
+    let mut summaries = load_summaries(&dir);
+    let mut next_id = summaries.last().map_or(1, |summary| summary.id + 1);
+
+    while let Ok(request) = mailbox.receive() {
+        let response = match request.data() {
+            ReplayRequest::Save(replay) => {
+                let replay = Replay {
+                    id: next_id,
+                    ..replay.clone()
+                };
+                next_id += 1;
+                let bytes = encode_replay(&replay).expect("failed to serialize replay");
+                store::write(&replay_path(&dir, replay.id), &bytes);
+                summaries.push(replay.summary());
+                ReplayResponse::Saved(replay.id)
+            }
+            ReplayRequest::Get(id) => ReplayResponse::Replay(
+                fs::read(replay_path(&dir, *id))
+                    .ok()
+                    .and_then(|bytes| decode_replay(&bytes).ok()),
+            ),
+            ReplayRequest::List(username) => ReplayResponse::ReplayList(
+                summaries
+                    .iter()
+                    .rev()
+                    .filter(|summary| summary.players.contains(username))
+                    .take(MAX_LISTED_REPLAYS)
+                    .cloned()
+                    .collect(),
+            ),
+        };
+        request.reply(response);
+    }
+}
+
+fn replay_path(dir: &str, id: ReplayId) -> String {
+    format!("{}/{}.replay", dir, id)
+}
+
+/// Summaries of the replays in `dir` sorted by id, unreadable files are skipped
+fn load_summaries(dir: &str) -> Vec<ReplaySummary> {
+    let mut summaries: Vec<_> = fs::read_dir(dir)
+        .expect("failed to read replay directory")
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "replay" {
+                return None;
+            }
+            let replay = fs::read(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| decode_replay(&bytes).map_err(|err| err.to_string()));
+            match replay {
+                Ok(replay) => Some(replay.summary()),
+                Err(err) => {
+                    warn!(path = %path.display(), error = %err, "skipping replay");
+                    None
+                }
+            }
+        })
+        .collect();
+    summaries.sort_by_key(|summary| summary.id);
+    summaries
+}
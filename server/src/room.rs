@@ -1,124 +1,545 @@
-use dipa::Diffable;
-use lunatic::{process::Process, Mailbox};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::Duration;
+use lunatic::{
+    process::{self, Process},
+    Mailbox, ReceiveError,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tracing::warn;
 
 use crate::client::ClientMsg;
-use shared::message::{GameAction, GameState, MessageFromServer, Username};
+use crate::coordinator::{CoordinatorMsg, CoordinatorRequest};
+use crate::logging::LogFilter;
+use crate::rating::{RatingMsg, RatingRequest};
+use crate::replay::{ReplayMsg, ReplayRequest, ReplayResponse};
+use crate::snapshot::{RoomSnapshot, SnapshotMsg, SnapshotRequest};
+use shared::{
+    message::{
+        ActionRejection, ErrorCode, MessageFromServer, RoomKind, RoomName, RoomOptions, RoomStatus,
+        Username,
+    },
+    replay::{Replay, ReplayId},
+    serialize::deserialize,
+    sync::{StateCheckpoint, SyncState},
+};
 
 #[derive(Serialize, Deserialize)]
 pub enum RoomMsg {
     JoinRoom(Client),
+    // joins as a read-only observer, see `Room::on_spectate`
+    Spectate(Client),
     LeaveRoom(Client),
     Drop(Username),
-    Action(Client, GameAction),
+    // the connection of the client dropped, it may still resume its session
+    Disconnect(Username),
+    // a dropped client resumed its session on a new connection
+    Reconnect(Client),
+    // serialized `Room::Action`, decoded by the room process
+    Action(Client, Vec<u8>),
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Client {
     pub username: Username,
     process: Process<ClientMsg>,
+    // logged in to the account of the username, set by the coordinator which
+    // knows the session of the client
+    pub logged_in: bool,
 }
 
 pub(crate) type HaveFullState = bool;
 
+/// Server wide processes that rooms and clients talk to
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Services {
+    pub rating: Process<RatingMsg>,
+    pub replays: Process<ReplayMsg>,
+    pub snapshots: Process<SnapshotMsg>,
+    // installed by every process that logs
+    pub log: LogFilter,
+}
+
 impl Client {
     pub fn new(username: Username, client_proc: Process<ClientMsg>) -> Self {
         Self {
             username,
             process: client_proc,
+            logged_in: false,
         }
     }
+
+    pub fn with_login(mut self, logged_in: bool) -> Self {
+        self.logged_in = logged_in;
+        self
+    }
 }
 
+/// State that can be broadcast to clients as a full snapshot or a dipa delta
+pub trait RoomState: SyncState {}
+
+impl<S: SyncState> RoomState for S {}
+
 pub trait Room {
-    fn new(room_name: String) -> Self;
-    fn on_join(&mut self, client: Client, ctx: &Context);
-    fn on_leave(&mut self, client: Client, ctx: &Context);
-    fn on_drop(&mut self, client_username: Username, ctx: &Context);
-    fn on_msg(&mut self, client: Client, msg: GameAction, ctx: &Context);
-    fn on_update(&mut self, delta_time: Duration, ctx: &Context) {}
-    fn update_interval() -> Option<Duration> {
+    type State: RoomState;
+    type Action: DeserializeOwned;
+
+    fn new(room_name: RoomName, options: &RoomOptions) -> Self;
+    /// Rebuild a room from `Room::snapshot` after a restart, `None` if the
+    /// snapshot cannot be used
+    fn restore(_room_name: RoomName, _options: &RoomOptions, _snapshot: &[u8]) -> Option<Self>
+    where
+        Self: Sized,
+    {
         None
     }
-    fn max_client() -> Option<usize> {
+    fn on_join(&mut self, client: Client, ctx: &mut Context<Self::State>);
+    /// Spectators receive broadcasts but their actions never reach `on_msg`.
+    /// They already got the last broadcast state when this is called.
+    fn on_spectate(&mut self, _client: Client, _ctx: &mut Context<Self::State>) {}
+    fn on_leave(&mut self, client: Client, ctx: &mut Context<Self::State>);
+    fn on_drop(&mut self, client_username: Username, ctx: &mut Context<Self::State>);
+    /// The connection of the client dropped. Its seat stays reserved until it
+    /// reconnects, or `on_drop` is called once `reservation_timeout` passed.
+    fn on_disconnect(&mut self, _client_username: Username, _ctx: &mut Context<Self::State>) {}
+    /// The connection of the client was replaced, the client already got
+    /// the last broadcast state when this is called
+    fn on_reconnect(&mut self, _client: Client, _ctx: &mut Context<Self::State>) {}
+    fn on_msg(&mut self, client: Client, msg: Self::Action, ctx: &mut Context<Self::State>);
+    fn on_update(&mut self, _delta_time: Duration, _ctx: &mut Context<Self::State>) {}
+    /// Called once after `Room::restore`. The clients that held a seat are
+    /// disconnected, their seats are reserved until they join the server again.
+    fn on_restore(&mut self, _ctx: &mut Context<Self::State>) {}
+    fn update_interval(&self) -> Option<Duration> {
+        None
+    }
+    fn max_client(_options: &RoomOptions) -> Option<usize> {
+        None
+    }
+    /// How long the seat of a disconnected client is reserved, `None` drops
+    /// the client right away
+    fn reservation_timeout(&self) -> Option<Duration> {
+        None
+    }
+    /// Reported to the coordinator whenever it changes, shown in room listings
+    fn status(&self) -> RoomStatus {
+        RoomStatus::default()
+    }
+    /// Serialized state saved after every change so that the room survives a
+    /// restart of the server, `None` for rooms that are not restored
+    fn snapshot(&self) -> Option<Vec<u8>> {
         None
     }
 }
 
-pub struct Context<'a> {
+pub struct Context<'a, S: RoomState> {
+    room: &'a Process<RoomMsg>,
+    services: &'a Services,
     clients: &'a mut HashMap<Username, (Process<ClientMsg>, HaveFullState)>,
-    prev_state: &'a mut Option<GameState>,
+    checkpoint: &'a mut StateCheckpoint<S>,
 }
 
-impl<'a> Context<'a> {
+impl<'a, S: RoomState> Context<'a, S> {
     pub(crate) fn new(
+        room: &'a Process<RoomMsg>,
+        services: &'a Services,
         clients: &'a mut HashMap<Username, (Process<ClientMsg>, HaveFullState)>,
-        state_checkpoint: &'a mut Option<GameState>,
+        checkpoint: &'a mut StateCheckpoint<S>,
     ) -> Self {
         Self {
+            room,
+            services,
             clients,
-            prev_state: state_checkpoint,
+            checkpoint,
         }
     }
 
-    pub fn broadcast(&self, state: &GameState) {
-        if let Some(prev_state) = &self.prev_state {
-            let delta = prev_state.create_delta_towards(state).delta;
-            let bytes = bincode::serialize(&delta).unwrap();
-            self.clients.values().for_each(|(client, have_full_state)| {
-                let msg = {
-                    if *have_full_state {
+    /// Process of the room itself, for processes that should talk to the room
+    pub fn room(&self) -> Process<RoomMsg> {
+        self.room.clone()
+    }
+
+    /// Log filter for processes spawned by the room
+    pub fn log_filter(&self) -> LogFilter {
+        self.services.log.clone()
+    }
+
+    /// Record a finished game between two players for their ratings,
+    /// `winner` is the index into `players` or `None` for a draw
+    pub fn report_game(&self, players: [Username; 2], winner: Option<usize>) {
+        self.services
+            .rating
+            .request(RatingRequest::ReportGame { players, winner })
+            .unwrap();
+    }
+
+    /// Store the replay of a finished game, its id is assigned by the store
+    pub fn save_replay(&self, replay: Replay) -> ReplayId {
+        match self
+            .services
+            .replays
+            .request(ReplayRequest::Save(replay))
+            .unwrap()
+        {
+            ReplayResponse::Saved(id) => id,
+            response => unreachable!("unexpected replay response {:?}", response),
+        }
+    }
+
+    /// Add a client that was not sent by the coordinator, e.g. a bot.
+    /// It receives the full state with the next broadcast.
+    pub fn insert(&mut self, client: Client) {
+        self.clients
+            .insert(client.username, (client.process, false));
+    }
+
+    /// Drop a client from the room and notify its process
+    pub fn remove(&mut self, username: &Username) {
+        if let Some((client, _)) = self.clients.remove(username) {
+            client.send(ClientMsg::ClientDropped);
+        }
+    }
+
+    /// Send a message to a single client in the room
+    pub fn send(&self, username: &Username, msg: MessageFromServer) {
+        if let Some((client, _)) = self.clients.get(username) {
+            client.send(ClientMsg::RoomMessage(msg));
+        }
+    }
+
+    /// Send the last broadcast state to a client that does not hold it yet
+    pub(crate) fn sync(&mut self, username: &Username) {
+        if let (Some(state), Some((client, have_full_state))) =
+            (self.checkpoint.state(), self.clients.get_mut(username))
+        {
+            let full_state = bincode::serialize(state).expect("failed to serialize state");
+            client.send(ClientMsg::RoomMessage(MessageFromServer::State(full_state)));
+            *have_full_state = true;
+        }
+    }
+
+    /// Send the state to every client in the room. Clients holding the
+    /// previous checkpoint get a delta, newcomers get the full state.
+    pub fn broadcast(&mut self, state: &S) {
+        let full_state = bincode::serialize(state).expect("failed to serialize state");
+        let delta = self
+            .checkpoint
+            .advance(state)
+            .expect("failed to serialize state delta");
+        self.clients
+            .values_mut()
+            .for_each(|(client, have_full_state)| {
+                let msg = match &delta {
+                    Some(bytes) if *have_full_state => {
                         MessageFromServer::StateChanged(bytes.clone())
-                    } else {
-                        MessageFromServer::State(state.clone())
                     }
+                    _ => MessageFromServer::State(full_state.clone()),
                 };
                 client.send(ClientMsg::RoomMessage(msg));
+                *have_full_state = true;
             });
-        } else {
-            self.clients.values().for_each(|(client, _)| {
-                client.send(ClientMsg::RoomMessage(MessageFromServer::State(
-                    state.clone(),
-                )));
-            });
-        }
     }
 }
 
-pub fn room_process<T: Room>(room_name: String, mailbox: Mailbox<RoomMsg>) {
+pub fn room_process<T: Room>(
+    (room_name, kind, options, coordinator, services, snapshot): (
+        RoomName,
+        RoomKind,
+        RoomOptions,
+        Process<CoordinatorMsg>,
+        Services,
+        Option<RoomSnapshot>,
+    ),
+    mailbox: Mailbox<RoomMsg>,
+) {
+    services.log.init();
+    let this = process::this(&mailbox);
     let mut clients = HashMap::<Username, (Process<ClientMsg>, HaveFullState)>::new();
-    let mut state_checkpoint: Option<GameState> = None;
-    let mut room = T::new(room_name);
+    let mut spectators = HashSet::<Username>::new();
+    // clients sent by the coordinator that hold a seat, unlike bots
+    let mut seated = HashSet::<Username>::new();
+    let mut state_checkpoint = StateCheckpoint::new();
+    // seats of disconnected clients and when their reservation runs out
+    let mut reserved = HashMap::<Username, Instant>::new();
+
+    let mut room = match snapshot {
+        Some(snapshot) => match T::restore(room_name.clone(), &options, &snapshot.state) {
+            Some(mut room) => {
+                // restored clients have no connection until they join the
+                // server again
+                let now = Instant::now();
+                let deadline = room
+                    .reservation_timeout()
+                    .and_then(|timeout| now.checked_add(timeout))
+                    .unwrap_or(now);
+                for username in snapshot.members {
+                    reserved.insert(username.clone(), deadline);
+                    seated.insert(username);
+                }
+                let mut context =
+                    Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                room.on_restore(&mut context);
+                room
+            }
+            None => {
+                warn!(room = %room_name, "could not restore room");
+                for username in snapshot.members {
+                    release_seat(&coordinator, username);
+                }
+                services
+                    .snapshots
+                    .request(SnapshotRequest::Remove(room_name))
+                    .unwrap();
+                return;
+            }
+        },
+        None => T::new(room_name.clone(), &options),
+    };
+
+    let update_interval = room.update_interval();
+    let mut last_update = Instant::now();
+    let mut last_status = RoomStatus::default();
+    let mut last_snapshot = RoomSnapshot {
+        name: room_name.clone(),
+        kind,
+        options,
+        members: Vec::new(),
+        state: Vec::new(),
+    };
+
+    loop {
+        let mut now = Instant::now();
+        let expired: Vec<_> = reserved
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(username, _)| username.clone())
+            .collect();
+        if !expired.is_empty() {
+            for username in expired {
+                reserved.remove(&username);
+                seated.remove(&username);
+                clients.remove(&username);
+                let mut context =
+                    Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                room.on_drop(username.clone(), &mut context);
+                release_seat(&coordinator, username);
+            }
+            if clients.is_empty() && reserved.is_empty() {
+                break;
+            }
+            report_status(&room, &mut last_status, &coordinator);
+        }
+
+        if let Some(interval) = update_interval {
+            let delta_time = now - last_update;
+            if delta_time >= interval {
+                last_update = now;
+                let mut context =
+                    Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                room.on_update(delta_time, &mut context);
+                report_status(&room, &mut last_status, &coordinator);
+                // an update that overran the interval still lets a pending
+                // message through before the next one
+                now = Instant::now();
+            }
+        }
+
+        // wake up in time for the next update or the end of a reservation
+        // unless a message arrives first
+        let next_update = update_interval.map(|interval| last_update + interval);
+        let message = match reserved.values().copied().chain(next_update).min() {
+            Some(wake_up) => match mailbox
+                .receive_with_timeout(timeout_millis(wake_up.saturating_duration_since(now)))
+            {
+                Ok(message) => message,
+                Err(ReceiveError::Timeout) => continue,
+                Err(_) => break,
+            },
+            None => match mailbox.receive() {
+                Ok(message) => message,
+                Err(_) => break,
+            },
+        };
 
-    while let Ok(message) = mailbox.receive() {
         match message {
             RoomMsg::JoinRoom(client) => {
                 clients.insert(client.username.clone(), (client.process.clone(), false));
-                let context = Context::new(&mut clients, &mut state_checkpoint);
-                room.on_join(client, &context);
+                seated.insert(client.username.clone());
+                let mut context =
+                    Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                room.on_join(client, &mut context);
+            }
+            RoomMsg::Spectate(client) => {
+                clients.insert(client.username.clone(), (client.process.clone(), false));
+                spectators.insert(client.username.clone());
+                let mut context =
+                    Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                context.sync(&client.username);
+                room.on_spectate(client, &mut context);
             }
             RoomMsg::LeaveRoom(client) => {
                 clients.remove(&client.username);
-                if clients.len() == 0 {
+                seated.remove(&client.username);
+                // spectators leave without the room noticing
+                if !spectators.remove(&client.username) && !clients.is_empty() {
+                    let mut context =
+                        Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                    room.on_leave(client.clone(), &mut context);
+                }
+                if clients.is_empty() && reserved.is_empty() {
                     break;
                 }
-                let context = Context::new(&mut clients, &mut state_checkpoint);
-                room.on_leave(client.clone(), &context);
             }
             RoomMsg::Drop(username) => {
-                clients.remove(&username);
-                let context = Context::new(&mut clients, &mut state_checkpoint);
-                room.on_drop(username.clone(), &context);
-                if clients.len() == 0 {
+                // the seat may have been given up already
+                let connected = clients.remove(&username).is_some();
+                if reserved.remove(&username).is_none() && !connected {
+                    continue;
+                }
+                seated.remove(&username);
+                if !spectators.remove(&username) {
+                    let mut context =
+                        Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                    room.on_drop(username.clone(), &mut context);
+                }
+                if clients.is_empty() && reserved.is_empty() {
                     break;
                 }
             }
+            RoomMsg::Disconnect(username) => {
+                if !clients.contains_key(&username) {
+                    continue;
+                }
+                let deadline = room
+                    .reservation_timeout()
+                    .and_then(|timeout| Instant::now().checked_add(timeout));
+                match deadline {
+                    Some(deadline) if !spectators.contains(&username) => {
+                        reserved.insert(username.clone(), deadline);
+                        let mut context =
+                            Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                        room.on_disconnect(username, &mut context);
+                    }
+                    _ => {
+                        clients.remove(&username);
+                        seated.remove(&username);
+                        if !spectators.remove(&username) {
+                            let mut context =
+                                Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                            room.on_drop(username.clone(), &mut context);
+                        }
+                        release_seat(&coordinator, username);
+                        if clients.is_empty() && reserved.is_empty() {
+                            break;
+                        }
+                    }
+                }
+            }
+            RoomMsg::Reconnect(client) => {
+                // the seat may have been given up already
+                if !clients.contains_key(&client.username)
+                    && !reserved.contains_key(&client.username)
+                {
+                    continue;
+                }
+                reserved.remove(&client.username);
+                clients.insert(client.username.clone(), (client.process.clone(), false));
+                let mut context =
+                    Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                context.sync(&client.username);
+                if !spectators.contains(&client.username) {
+                    room.on_reconnect(client, &mut context);
+                }
+            }
             RoomMsg::Action(client, action) => {
-                let context = Context::new(&mut clients, &mut state_checkpoint);
-                room.on_msg(client, action, &context);
+                let mut context =
+                    Context::new(&this, &services, &mut clients, &mut state_checkpoint);
+                if spectators.contains(&client.username) {
+                    context.send(
+                        &client.username,
+                        MessageFromServer::ActionRejected(ActionRejection::NotAPlayer),
+                    );
+                    continue;
+                }
+                match deserialize::<T::Action>(&action) {
+                    Ok(action) => room.on_msg(client, action, &mut context),
+                    Err(err) => context.send(
+                        &client.username,
+                        MessageFromServer::error(ErrorCode::MalformedMessage, err.to_string()),
+                    ),
+                }
             }
         }
+        report_status(&room, &mut last_status, &coordinator);
+        save_snapshot(&room, &seated, &mut last_snapshot, &services);
+    }
+
+    // the room closed normally, there is nothing to restore
+    services
+        .snapshots
+        .request(SnapshotRequest::Remove(room_name))
+        .unwrap();
+}
+
+/// Save the snapshot of the room if its state or its members changed
+fn save_snapshot<T: Room>(
+    room: &T,
+    seated: &HashSet<Username>,
+    last_snapshot: &mut RoomSnapshot,
+    services: &Services,
+) {
+    let state = match room.snapshot() {
+        Some(state) => state,
+        None => return,
+    };
+    let mut members: Vec<_> = seated.iter().cloned().collect();
+    members.sort();
+    if members.is_empty() {
+        // rooms of spectators alone are not restored
+        if !last_snapshot.members.is_empty() {
+            last_snapshot.members.clear();
+            last_snapshot.state.clear();
+            services
+                .snapshots
+                .request(SnapshotRequest::Remove(last_snapshot.name.clone()))
+                .unwrap();
+        }
+        return;
+    }
+    if state != last_snapshot.state || members != last_snapshot.members {
+        last_snapshot.state = state;
+        last_snapshot.members = members;
+        services
+            .snapshots
+            .request(SnapshotRequest::Save(last_snapshot.clone()))
+            .unwrap();
+    }
+}
+
+/// Let the coordinator know about changes of the room status
+fn report_status<T: Room>(
+    room: &T,
+    last_status: &mut RoomStatus,
+    coordinator: &Process<CoordinatorMsg>,
+) {
+    let status = room.status();
+    if status != *last_status {
+        *last_status = status.clone();
+        coordinator
+            .request(CoordinatorRequest::UpdateRoomStatus(status))
+            .unwrap();
     }
 }
+
+/// Let the coordinator know that a disconnected client lost its place in
+/// the room
+fn release_seat(coordinator: &Process<CoordinatorMsg>, username: Username) {
+    coordinator
+        .request(CoordinatorRequest::ReleaseSeat(username))
+        .unwrap();
+}
+
+/// Round up so that a timeout never fires before the update is due
+pub(crate) fn timeout_millis(duration: Duration) -> u32 {
+    let millis = duration.as_micros().div_ceil(1000);
+    millis.clamp(1, u32::MAX as u128) as u32
+}
@@ -0,0 +1,89 @@
+use lunatic::{Mailbox, ReceiveError, Request};
+use serde::{Deserialize, Serialize};
+use shared::message::{RoomKind, RoomName, RoomOptions, Username};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::room::timeout_millis;
+use crate::store;
+
+/// How long changes are collected before the file is written again
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Everything needed to bring a room back after a restart
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomSnapshot {
+    pub name: RoomName,
+    pub kind: RoomKind,
+    pub options: RoomOptions,
+    // clients holding a seat, spectators are not restored
+    pub members: Vec<Username>,
+    // serialized by `Room::snapshot`
+    pub state: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SnapshotRequest {
+    Save(RoomSnapshot), // -> Saved
+    Remove(RoomName),   // -> Removed
+    Load,               // -> Snapshots
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SnapshotResponse {
+    Saved,
+    Removed,
+    Snapshots(Vec<RoomSnapshot>),
+}
+
+pub(crate) type SnapshotMsg = Request<SnapshotRequest, SnapshotResponse>;
+
+/// Keeps the latest snapshot of every live room in the file at `path`.
+/// Rooms save a snapshot whenever they change and remove it once they close,
+/// so whatever is left in the file on startup was interrupted. The changes
+/// are written at most once per `SAVE_INTERVAL`, rooms never wait for the
+/// disk.
+pub fn snapshot_process(path: String, mailbox: Mailbox<SnapshotMsg>) {
+    let mut snapshots: HashMap<RoomName, RoomSnapshot> = store::load(&path);
+    // changes that are not written yet
+    let mut dirty = false;
+    let mut last_save = Instant::now();
+
+    loop {
+        let request = if dirty {
+            let next_save = last_save + SAVE_INTERVAL;
+            mailbox.receive_with_timeout(timeout_millis(
+                next_save.saturating_duration_since(Instant::now()),
+            ))
+        } else {
+            mailbox.receive()
+        };
+        match request {
+            Ok(request) => {
+                let response = match request.data() {
+                    SnapshotRequest::Save(snapshot) => {
+                        snapshots.insert(snapshot.name.clone(), snapshot.clone());
+                        dirty = true;
+                        SnapshotResponse::Saved
+                    }
+                    SnapshotRequest::Remove(room_name) => {
+                        dirty |= snapshots.remove(room_name).is_some();
+                        SnapshotResponse::Removed
+                    }
+                    SnapshotRequest::Load => {
+                        SnapshotResponse::Snapshots(snapshots.values().cloned().collect())
+                    }
+                };
+                request.reply(response);
+            }
+            Err(ReceiveError::Timeout) => {}
+            Err(_) => break,
+        }
+
+        if dirty && last_save.elapsed() >= SAVE_INTERVAL {
+            store::save(&path, &snapshots);
+            dirty = false;
+            last_save = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+
+/// Read a bincode encoded value from `path`, the default if the file does
+/// not exist yet
+pub fn load<T: DeserializeOwned + Default>(path: &str) -> T {
+    match fs::read(path) {
+        Ok(bytes) => bincode::deserialize(&bytes)
+            .unwrap_or_else(|err| panic!("corrupted store {}: {}", path, err)),
+        Err(_) => T::default(),
+    }
+}
+
+pub fn save<T: Serialize>(path: &str, value: &T) {
+    let bytes = bincode::serialize(value).expect("failed to serialize store");
+    write(path, &bytes);
+}
+
+/// Write to a temporary file first so that a crash never leaves a
+/// half-written file behind
+pub fn write(path: &str, bytes: &[u8]) {
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, bytes).unwrap_or_else(|err| panic!("failed to write {}: {}", path, err));
+    fs::rename(&tmp_path, path).unwrap_or_else(|err| panic!("failed to write {}: {}", path, err));
+}
@@ -0,0 +1,438 @@
+use lunatic::{
+    net::TcpStream,
+    process::{self, Process},
+    Mailbox, Request,
+};
+use rustls::{pki_types::CertificateDer, ServerConfig, ServerConnection};
+use serde::{Deserialize, Serialize};
+use shared::{
+    frame::{encode_frame, FrameDecoder},
+    transport::{Incoming, ReadError, Transport, TransportReader, TransportWriter},
+};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use crate::transport::ClientStream;
+
+/// Size of the buffer each read from the socket goes into
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Certificate chain and private key in PEM format, checked once on startup
+/// and turned into a rustls config by every connection
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TlsIdentity {
+    certs: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl TlsIdentity {
+    /// Read and check the files of the `network.tls_cert` and
+    /// `network.tls_key` options, errors are labeled with the option at fault
+    pub fn load(cert_path: &str, key_path: &str) -> Result<Self, String> {
+        let read =
+            |path: &str| fs::read(path).map_err(|err| format!("cannot read {}: {}", path, err));
+        let identity = Self {
+            certs: read(cert_path).map_err(|err| format!("network.tls_cert: {}", err))?,
+            key: read(key_path).map_err(|err| format!("network.tls_key: {}", err))?,
+        };
+        identity
+            .parse_certs()
+            .map_err(|err| format!("network.tls_cert: {}", err))?;
+        identity
+            .server_config()
+            .map_err(|err| format!("network.tls_key: {}", err))?;
+        Ok(identity)
+    }
+
+    fn parse_certs(&self) -> Result<Vec<CertificateDer<'static>>, String> {
+        let certs = rustls_pemfile::certs(&mut &self.certs[..])
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid certificate: {}", err))?;
+        if certs.is_empty() {
+            return Err("no certificate found".to_string());
+        }
+        Ok(certs)
+    }
+
+    fn server_config(&self) -> Result<ServerConfig, String> {
+        let certs = self.parse_certs()?;
+        let key = rustls_pemfile::private_key(&mut &self.key[..])
+            .map_err(|err| format!("invalid private key: {}", err))?
+            .ok_or_else(|| "no private key found".to_string())?;
+        ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|err| format!("certificate does not match the key: {}", err))
+    }
+}
+
+// keep the key out of the logs
+impl fmt::Debug for TlsIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsIdentity").finish_non_exhaustive()
+    }
+}
+
+/// Length-prefixed frames over TLS. A rustls connection cannot be shared
+/// between processes, so both halves go through a process owning it, which
+/// gets the records of the client from a process blocking on the socket.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TlsTransport {
+    stream: ClientStream,
+    identity: TlsIdentity,
+}
+
+impl TlsTransport {
+    pub fn new(stream: ClientStream, identity: TlsIdentity) -> Self {
+        Self { stream, identity }
+    }
+}
+
+impl Transport for TlsTransport {
+    type Reader = TlsReader;
+    type Writer = TlsWriter;
+
+    fn split(self) -> (Self::Reader, Self::Writer) {
+        let tls = process::spawn_with(
+            (self.stream.clone().into_inner(), self.identity),
+            tls_process,
+        )
+        .unwrap();
+        let socket = process::spawn_with((tls.clone(), self.stream), socket_process).unwrap();
+        // go down together with the client process
+        tls.link();
+        socket.link();
+        (TlsReader(tls.clone()), TlsWriter(tls))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum TlsRequest {
+    Read,           // -> Message, Closed or Malformed
+    Write(Vec<u8>), // -> Written or Closed
+    Close,          // -> Written
+
+    // Sent by the socket process
+    // records of the client -> Processed, or Closed once nothing more is read
+    Received(Vec<u8>),
+    // the client closed the socket or stayed silent for too long -> Processed
+    SocketClosed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TlsResponse {
+    Message(Vec<u8>),
+    Written,
+    Processed,
+    Closed,
+    Malformed(String),
+}
+
+pub(crate) type TlsMsg = Request<TlsRequest, TlsResponse>;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TlsReader(Process<TlsMsg>);
+
+impl TransportReader for TlsReader {
+    fn read_message(&mut self) -> Result<Incoming, ReadError> {
+        match self.0.request(TlsRequest::Read) {
+            Ok(TlsResponse::Message(payload)) => Ok(Incoming::Message(payload)),
+            Ok(TlsResponse::Malformed(reason)) => Err(ReadError::Malformed(reason)),
+            _ => Err(ReadError::Closed),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TlsWriter(Process<TlsMsg>);
+
+impl TransportWriter for TlsWriter {
+    fn write_message(&mut self, payload: &[u8]) -> bincode::Result<()> {
+        match self.0.request(TlsRequest::Write(encode_frame(payload)?)) {
+            Ok(TlsResponse::Written) => Ok(()),
+            _ => Err(Box::new(bincode::ErrorKind::Io(
+                io::ErrorKind::BrokenPipe.into(),
+            ))),
+        }
+    }
+
+    fn write_reply(&mut self, reply: &[u8]) -> io::Result<()> {
+        match self.0.request(TlsRequest::Write(reply.to_vec())) {
+            Ok(TlsResponse::Written) => Ok(()),
+            _ => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn close(&mut self) {
+        let _ = self.0.request(TlsRequest::Close);
+    }
+}
+
+/// Owns the TLS connection of one client. Answers the requests of the
+/// client process and of the socket process as they come in, a read waits
+/// until the socket process delivered a whole message.
+pub fn tls_process((mut stream, identity): (TcpStream, TlsIdentity), mailbox: Mailbox<TlsMsg>) {
+    let mut session = TlsSession::new(&identity);
+    // read request of the reader waiting for the next message
+    let mut pending_read: Option<TlsMsg> = None;
+    // answer to every read once nothing more can be read
+    let mut read_error: Option<TlsResponse> = None;
+
+    while let Ok(request) = mailbox.receive() {
+        match request.data() {
+            TlsRequest::Read => pending_read = Some(request),
+            TlsRequest::Write(bytes) => {
+                let written = session.write(bytes, &mut stream);
+                request.reply(match written {
+                    Ok(()) => TlsResponse::Written,
+                    Err(_) => TlsResponse::Closed,
+                });
+            }
+            TlsRequest::Close => {
+                session.close(&mut stream);
+                request.reply(TlsResponse::Written);
+                return;
+            }
+            TlsRequest::Received(records) => {
+                if read_error.is_none() {
+                    read_error = session.receive(records, &mut stream).err();
+                }
+                request.reply(match read_error {
+                    Some(_) => TlsResponse::Closed,
+                    None => TlsResponse::Processed,
+                });
+            }
+            TlsRequest::SocketClosed => {
+                read_error.get_or_insert(TlsResponse::Closed);
+                request.reply(TlsResponse::Processed);
+            }
+        }
+
+        if let Some(request) = pending_read.take() {
+            match session.decoder.next_frame() {
+                Ok(Some(frame)) => request.reply(TlsResponse::Message(frame)),
+                Ok(None) => match &read_error {
+                    Some(response) => request.reply(response.clone()),
+                    None => pending_read = Some(request),
+                },
+                // oversized frame, the stream cannot be recovered
+                Err(err) => {
+                    let response = TlsResponse::Malformed(err.to_string());
+                    request.reply(response.clone());
+                    read_error = Some(response);
+                }
+            }
+        }
+    }
+}
+
+/// Read the socket until it closes and hand everything to the TLS process.
+/// The client is dropped after the read timeout of the stream.
+pub fn socket_process((tls, mut stream): (Process<TlsMsg>, ClientStream), _: Mailbox<()>) {
+    let mut buffer = [0u8; READ_BUFFER_SIZE];
+    loop {
+        let request = match stream.read(&mut buffer) {
+            Ok(size) if size > 0 => TlsRequest::Received(buffer[..size].to_vec()),
+            _ => TlsRequest::SocketClosed,
+        };
+        let closed = matches!(request, TlsRequest::SocketClosed);
+        match tls.request(request) {
+            Ok(TlsResponse::Processed) if !closed => {}
+            _ => return,
+        }
+    }
+}
+
+/// Server side of a TLS connection without the socket: records of the client
+/// are passed in and the records for the client are written to `out`
+struct TlsSession {
+    tls: ServerConnection,
+    // plaintext of the client
+    decoder: FrameDecoder,
+}
+
+impl TlsSession {
+    fn new(identity: &TlsIdentity) -> Self {
+        let config = identity
+            .server_config()
+            .expect("the TLS identity is checked on startup");
+        Self {
+            tls: ServerConnection::new(Arc::new(config)).unwrap(),
+            decoder: FrameDecoder::new(),
+        }
+    }
+
+    /// Pass records through the TLS connection and their plaintext on to the
+    /// decoder, answering the handshake on `out`
+    fn receive<W: Write>(&mut self, mut records: &[u8], out: &mut W) -> Result<(), TlsResponse> {
+        while !records.is_empty() {
+            self.tls
+                .read_tls(&mut records)
+                .map_err(|err| TlsResponse::Malformed(err.to_string()))?;
+            let state = self.tls.process_new_packets();
+            // handshake messages, or the alert explaining an error
+            let _ = self.flush(out);
+            let state = state.map_err(|err| TlsResponse::Malformed(err.to_string()))?;
+
+            let mut plaintext = vec![0; state.plaintext_bytes_to_read()];
+            self.tls
+                .reader()
+                .read_exact(&mut plaintext)
+                .map_err(|_| TlsResponse::Closed)?;
+            self.decoder.extend(&plaintext);
+            if state.peer_has_closed() {
+                return Err(TlsResponse::Closed);
+            }
+        }
+        Ok(())
+    }
+
+    fn write<W: Write>(&mut self, plaintext: &[u8], out: &mut W) -> io::Result<()> {
+        self.tls.writer().write_all(plaintext)?;
+        self.flush(out)
+    }
+
+    fn close<W: Write>(&mut self, out: &mut W) {
+        self.tls.send_close_notify();
+        let _ = self.flush(out);
+    }
+
+    fn flush<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        while self.tls.wants_write() {
+            self.tls.write_tls(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
+    use std::convert::TryFrom;
+
+    fn self_signed() -> CertifiedKey {
+        generate_simple_self_signed(vec!["localhost".to_string()]).unwrap()
+    }
+
+    fn client(certified: &CertifiedKey) -> ClientConnection {
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from("localhost").unwrap();
+        ClientConnection::new(Arc::new(config), name).unwrap()
+    }
+
+    fn session(certified: &CertifiedKey) -> TlsSession {
+        let identity = TlsIdentity {
+            certs: certified.cert.pem().into_bytes(),
+            key: certified.key_pair.serialize_pem().into_bytes(),
+        };
+        TlsSession::new(&identity)
+    }
+
+    /// Records the client wants to send
+    fn records(client: &mut ClientConnection) -> Vec<u8> {
+        let mut records = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut records).unwrap();
+        }
+        records
+    }
+
+    /// Pass the records of the server to the client and return the
+    /// plaintext in them
+    fn deliver(client: &mut ClientConnection, mut records: &[u8]) -> Vec<u8> {
+        let mut plaintext = Vec::new();
+        while !records.is_empty() {
+            client.read_tls(&mut records).unwrap();
+            let state = client.process_new_packets().unwrap();
+            let mut read = vec![0; state.plaintext_bytes_to_read()];
+            client.reader().read_exact(&mut read).unwrap();
+            plaintext.extend(read);
+        }
+        plaintext
+    }
+
+    #[test]
+    fn handshake_and_round_trip() {
+        let certified = self_signed();
+        let mut client = client(&certified);
+        let mut server = session(&certified);
+
+        while client.is_handshaking() {
+            let mut answer = Vec::new();
+            server.receive(&records(&mut client), &mut answer).unwrap();
+            deliver(&mut client, &answer);
+        }
+
+        client
+            .writer()
+            .write_all(&encode_frame(b"ping").unwrap())
+            .unwrap();
+        // the answer starts with the session tickets of the server
+        let mut answer = Vec::new();
+        server.receive(&records(&mut client), &mut answer).unwrap();
+        assert_eq!(server.decoder.next_frame().unwrap(), Some(b"ping".to_vec()));
+
+        server
+            .write(&encode_frame(b"pong").unwrap(), &mut answer)
+            .unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&deliver(&mut client, &answer));
+        assert_eq!(decoder.next_frame().unwrap(), Some(b"pong".to_vec()));
+
+        client.send_close_notify();
+        assert!(matches!(
+            server.receive(&records(&mut client), &mut io::sink()),
+            Err(TlsResponse::Closed)
+        ));
+    }
+
+    #[test]
+    fn reject_records_that_are_not_tls() {
+        let mut server = session(&self_signed());
+        let mut alert = Vec::new();
+        assert!(matches!(
+            server.receive(b"GET / HTTP/1.1\r\n\r\n", &mut alert),
+            Err(TlsResponse::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn label_load_errors_with_the_option_at_fault() {
+        let dir = std::env::temp_dir().join(format!("tls-load-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        let certified = self_signed();
+        fs::write(&cert, certified.cert.pem()).unwrap();
+        let load = || TlsIdentity::load(cert.to_str().unwrap(), key.to_str().unwrap());
+
+        let err = load().unwrap_err();
+        assert!(err.starts_with("network.tls_key: cannot read"), "{}", err);
+        fs::write(&key, "not a key").unwrap();
+        let err = load().unwrap_err();
+        assert!(
+            err.starts_with("network.tls_key: no private key found"),
+            "{}",
+            err
+        );
+        fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        assert!(load().is_ok());
+        fs::write(&cert, "not a certificate").unwrap();
+        let err = load().unwrap_err();
+        assert!(
+            err.starts_with("network.tls_cert: no certificate found"),
+            "{}",
+            err
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
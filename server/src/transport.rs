@@ -0,0 +1,100 @@
+use lunatic::{net::TcpStream, process::Process, Mailbox};
+use serde::{Deserialize, Serialize};
+use shared::{
+    message::MessageFromClient,
+    serialize::deserialize,
+    transport::{Incoming, ReadError, TransportReader},
+};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::client::ClientMsg;
+
+/// TCP stream of a client that drops it after `read_timeout` without
+/// reading anything. The timeout is not carried over when the stream is sent to
+/// another process, so it is set again by the first read in a process.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientStream {
+    stream: TcpStream,
+    read_timeout: Duration,
+    #[serde(skip)]
+    timeout_set: bool,
+}
+
+impl ClientStream {
+    pub fn new(stream: TcpStream, read_timeout: Duration) -> Self {
+        Self {
+            stream,
+            read_timeout,
+            timeout_set: false,
+        }
+    }
+
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.timeout_set {
+            self.stream.set_read_timeout(Some(self.read_timeout));
+            self.timeout_set = true;
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Read messages until the connection drops and forward them to the client
+/// process, along with the replies it has to write. Runs in its own process
+/// linked to the client process.
+pub fn reader_process<R: TransportReader>(
+    (client, mut reader): (Process<ClientMsg>, R),
+    _: Mailbox<()>,
+) {
+    loop {
+        match reader.read_message() {
+            Ok(Incoming::Message(payload)) => {
+                if !forward(&client, &payload) {
+                    return;
+                }
+            }
+            Ok(Incoming::Reply(reply)) => client.send(ClientMsg::Reply(reply)),
+            Err(ReadError::Closed) => {
+                client.send(ClientMsg::ClientDropped);
+                return;
+            }
+            Err(ReadError::Malformed(reason)) => {
+                client.send(ClientMsg::MalformedMessage(reason));
+                client.send(ClientMsg::ClientDropped);
+                return;
+            }
+        }
+    }
+}
+
+/// Pass a message on to the client process. Returns false once the client
+/// left the server and nothing more should be read.
+fn forward(client: &Process<ClientMsg>, payload: &[u8]) -> bool {
+    match deserialize::<MessageFromClient>(payload) {
+        Ok(msg) => {
+            let leaving = matches!(msg, MessageFromClient::LeaveServer);
+            client.send(ClientMsg::MessageFromClient(msg));
+            !leaving
+        }
+        Err(err) => {
+            client.send(ClientMsg::MalformedMessage(err.to_string()));
+            true
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use crate::message::GameState;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Score of a won position, moves that win sooner score higher
+const WIN_SCORE: i32 = 1_000_000;
+
+/// Boards with at most this many empty cells are solved completely
+const FULL_SEARCH_EMPTY_CELLS: usize = 9;
+
+/// The transposition table is cleared once it holds more entries than this
+const MAX_TABLE_ENTRIES: usize = 1 << 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    /// Probability of playing a random move instead of the best one
+    fn mistake_rate(self) -> f64 {
+        match self {
+            Difficulty::Easy => 0.5,
+            Difficulty::Medium => 0.2,
+            Difficulty::Hard => 0.0,
+        }
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "easy" => Ok(Difficulty::Easy),
+            "medium" => Ok(Difficulty::Medium),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(format!("unknown difficulty {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Medium => "medium",
+            Difficulty::Hard => "hard",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    score: i32,
+    depth: usize,
+    bound: Bound,
+}
+
+/// Negamax search with alpha-beta pruning and a transposition table.
+/// Small boards are solved perfectly, larger ones are searched to a fixed
+/// depth with a heuristic evaluation of the open lines.
+#[derive(Default)]
+pub struct Solver {
+    table: HashMap<(Vec<Vec<i8>>, i8), Entry>,
+}
+
+impl Solver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pick the move for the player whose turn it is. Depending on the
+    /// difficulty some moves are picked at random instead.
+    pub fn choose_move<R: Rng>(
+        &mut self,
+        state: &GameState,
+        difficulty: Difficulty,
+        rng: &mut R,
+    ) -> Option<(usize, usize)> {
+        if rng.gen_bool(difficulty.mistake_rate()) {
+            empty_cells(state).choose(rng).copied()
+        } else {
+            self.best_move(state, rng)
+        }
+    }
+
+    /// One of the best moves for the player whose turn it is, or `None` if
+    /// the game is over
+    pub fn best_move<R: Rng>(&mut self, state: &GameState, rng: &mut R) -> Option<(usize, usize)> {
+        if state.is_gameover() {
+            return None;
+        }
+        let mut state = state.clone();
+        let color = state.turn as i8;
+        let depth = search_depth(&state);
+        if depth < empty_cells(&state).len() || self.table.len() > MAX_TABLE_ENTRIES {
+            // heuristic scores depend on the horizon of the search they came
+            // from, only complete searches can be reused by the next move
+            self.table.clear();
+        }
+
+        let mut best_moves = Vec::new();
+        let mut best_score = -WIN_SCORE * 2;
+        for (x, y) in candidate_moves(&state, depth) {
+            let score = self.score_move(
+                &mut state,
+                (x, y),
+                color,
+                depth,
+                -WIN_SCORE * 2,
+                WIN_SCORE * 2,
+            );
+            if score > best_score {
+                best_score = score;
+                best_moves.clear();
+            }
+            if score == best_score {
+                best_moves.push((x, y));
+            }
+        }
+        best_moves.choose(rng).copied()
+    }
+
+    /// Score of placing `color` at x y from the point of view of `color`
+    fn score_move(
+        &mut self,
+        state: &mut GameState,
+        (x, y): (usize, usize),
+        color: i8,
+        depth: usize,
+        alpha: i32,
+        beta: i32,
+    ) -> i32 {
+        state.board[x][y] = color;
+        let score = if state.check_winner(x, y).is_some() {
+            WIN_SCORE + depth as i32
+        } else if state.is_board_full() {
+            0
+        } else {
+            -self.negamax(state, 1 - color, depth - 1, -beta, -alpha)
+        };
+        state.board[x][y] = -1;
+        score
+    }
+
+    fn negamax(
+        &mut self,
+        state: &mut GameState,
+        color: i8,
+        depth: usize,
+        mut alpha: i32,
+        mut beta: i32,
+    ) -> i32 {
+        if depth == 0 {
+            return evaluate(state, color);
+        }
+
+        let key = (state.board.clone(), color);
+        let alpha_orig = alpha;
+        if let Some(entry) = self.table.get(&key) {
+            if entry.depth >= depth {
+                match entry.bound {
+                    Bound::Exact => return entry.score,
+                    Bound::Lower => alpha = max(alpha, entry.score),
+                    Bound::Upper => beta = min(beta, entry.score),
+                }
+                if alpha >= beta {
+                    return entry.score;
+                }
+            }
+        }
+
+        let mut best = -WIN_SCORE * 2;
+        for (x, y) in candidate_moves(state, depth) {
+            let score = self.score_move(state, (x, y), color, depth, alpha, beta);
+            best = max(best, score);
+            alpha = max(alpha, score);
+            if alpha >= beta {
+                break;
+            }
+        }
+
+        let bound = if best <= alpha_orig {
+            Bound::Upper
+        } else if best >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        self.table.insert(
+            key,
+            Entry {
+                score: best,
+                depth,
+                bound,
+            },
+        );
+        best
+    }
+}
+
+fn empty_cells(state: &GameState) -> Vec<(usize, usize)> {
+    (0..state.height())
+        .flat_map(|x| (0..state.width()).map(move |y| (x, y)))
+        .filter(|&(x, y)| state.board[x][y] == -1)
+        .collect()
+}
+
+fn search_depth(state: &GameState) -> usize {
+    let empty = empty_cells(state).len();
+    if empty <= FULL_SEARCH_EMPTY_CELLS {
+        empty
+    } else if state.width() * state.height() <= 25 {
+        min(4, empty)
+    } else {
+        min(2, empty)
+    }
+}
+
+/// Every empty cell when solving completely, otherwise only the empty cells
+/// next to an existing node to keep the branching factor manageable
+fn candidate_moves(state: &GameState, depth: usize) -> Vec<(usize, usize)> {
+    let empty = empty_cells(state);
+    if depth >= empty.len() {
+        return empty;
+    }
+    let near_node = |&(x, y): &(usize, usize)| {
+        (x.saturating_sub(1)..min(x + 2, state.height())).any(|i| {
+            (y.saturating_sub(1)..min(y + 2, state.width())).any(|j| state.board[i][j] != -1)
+        })
+    };
+    let candidates: Vec<_> = empty.iter().copied().filter(near_node).collect();
+    if candidates.is_empty() {
+        vec![(state.height() / 2, state.width() / 2)]
+    } else {
+        candidates
+    }
+}
+
+/// Heuristic for positions at the search horizon: every window of
+/// `win_length` cells that only one player occupies counts for that player
+fn evaluate(state: &GameState, color: i8) -> i32 {
+    let directions = [(0, 1), (1, 0), (1, 1), (1, -1)];
+    let k = state.win_length() as isize;
+    let mut score = 0;
+    for x in 0..state.height() as isize {
+        for y in 0..state.width() as isize {
+            for &(dx, dy) in directions.iter() {
+                let (end_x, end_y) = (x + dx * (k - 1), y + dy * (k - 1));
+                if end_x < 0
+                    || end_y < 0
+                    || end_x >= state.height() as isize
+                    || end_y >= state.width() as isize
+                {
+                    continue;
+                }
+                let (mut own, mut other) = (0, 0);
+                for i in 0..k {
+                    match state.board[(x + dx * i) as usize][(y + dy * i) as usize] {
+                        -1 => {}
+                        c if c == color => own += 1,
+                        _ => other += 1,
+                    }
+                }
+                if other == 0 {
+                    score += own * own;
+                } else if own == 0 {
+                    score -= other * other;
+                }
+            }
+        }
+    }
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Outcome;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const PLAYERS: [&str; 2] = ["player_a", "player_b"];
+
+    fn started_game(width: usize, height: usize, win_length: usize) -> GameState {
+        let mut game = GameState::new(width, height, win_length);
+        game.players.extend(PLAYERS.iter().map(|s| s.to_string()));
+        game.started = true;
+        game
+    }
+
+    fn play(game: &mut GameState, x: usize, y: usize) {
+        let player = game.players[game.turn as usize].clone();
+        game.validate_place_node(x, y, &player).unwrap();
+        game.place_node(x, y, &player);
+        game.turn = (game.turn == 0) as u8;
+    }
+
+    #[test]
+    fn takes_the_win() {
+        let mut game = started_game(3, 3, 3);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+            play(&mut game, *x, *y);
+        }
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(Solver::new().best_move(&game, &mut rng), Some((0, 2)));
+    }
+
+    #[test]
+    fn blocks_the_loss() {
+        let mut game = started_game(3, 3, 3);
+        for (x, y) in [(0, 0), (1, 1), (0, 1)].iter() {
+            play(&mut game, *x, *y);
+        }
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(Solver::new().best_move(&game, &mut rng), Some((0, 2)));
+    }
+
+    /// Let the opponent try every possible move and make sure the solver
+    /// never ends up losing
+    fn never_loses(game: &GameState, bot: u8, solver: &mut Solver, rng: &mut StdRng) {
+        match game.outcome() {
+            Outcome::Win { player, .. } => {
+                assert_eq!(player, bot as i8, "{:?}", game.board);
+                return;
+            }
+            Outcome::Draw => return,
+            Outcome::InProgress => {}
+            Outcome::TimedOut { .. } => unreachable!(),
+        }
+        if game.turn == bot {
+            let (x, y) = solver.best_move(game, rng).unwrap();
+            let mut next = game.clone();
+            play(&mut next, x, y);
+            never_loses(&next, bot, solver, rng);
+        } else {
+            for (x, y) in empty_cells(game) {
+                let mut next = game.clone();
+                play(&mut next, x, y);
+                never_loses(&next, bot, solver, rng);
+            }
+        }
+    }
+
+    #[test]
+    fn perfect_play_never_loses() {
+        let mut solver = Solver::new();
+        let mut rng = StdRng::seed_from_u64(1);
+        let game = started_game(3, 3, 3);
+        never_loses(&game, 0, &mut solver, &mut rng);
+        never_loses(&game, 1, &mut solver, &mut rng);
+    }
+
+    #[test]
+    fn perfect_play_against_itself_is_a_draw() {
+        let mut solver = Solver::new();
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut game = started_game(3, 3, 3);
+        while let Some((x, y)) = solver.best_move(&game, &mut rng) {
+            play(&mut game, x, y);
+        }
+        assert_eq!(game.outcome(), Outcome::Draw);
+    }
+
+    #[test]
+    fn difficulty_controls_mistakes() {
+        let mut game = started_game(3, 3, 3);
+        for (x, y) in [(0, 0), (1, 1), (0, 1)].iter() {
+            play(&mut game, *x, *y);
+        }
+        let mut solver = Solver::new();
+        let mut rng = StdRng::seed_from_u64(3);
+        let mistakes = |difficulty, solver: &mut Solver, rng: &mut StdRng| {
+            (0..200)
+                .filter(|_| solver.choose_move(&game, difficulty, rng) != Some((0, 2)))
+                .count()
+        };
+        assert_eq!(mistakes(Difficulty::Hard, &mut solver, &mut rng), 0);
+        assert!(mistakes(Difficulty::Medium, &mut solver, &mut rng) > 0);
+        assert!(
+            mistakes(Difficulty::Easy, &mut solver, &mut rng)
+                > mistakes(Difficulty::Medium, &mut solver, &mut rng)
+        );
+    }
+
+    #[test]
+    fn blocks_four_in_a_row_in_gomoku() {
+        let mut game = started_game(15, 15, 5);
+        // the opponent lines up four with one end blocked
+        for (x, y) in [(7, 7), (6, 7), (8, 7), (7, 6), (9, 7), (6, 6), (10, 7)].iter() {
+            play(&mut game, *x, *y);
+        }
+        let mut rng = StdRng::seed_from_u64(4);
+        assert_eq!(Solver::new().best_move(&game, &mut rng), Some((11, 7)));
+    }
+
+    #[test]
+    fn heuristic_searches_start_from_an_empty_table() {
+        let mut game = started_game(5, 5, 4);
+        for (x, y) in [(2, 2), (1, 1), (2, 1)].iter() {
+            play(&mut game, *x, *y);
+        }
+        let mut next = game.clone();
+        play(&mut next, 2, 3);
+
+        let mut solver = Solver::new();
+        solver.best_move(&game, &mut StdRng::seed_from_u64(5));
+        let reused = solver.best_move(&next, &mut StdRng::seed_from_u64(6));
+        let mut fresh_solver = Solver::new();
+        let fresh = fresh_solver.best_move(&next, &mut StdRng::seed_from_u64(6));
+        assert_eq!(reused, fresh);
+        assert_eq!(solver.table.len(), fresh_solver.table.len());
+    }
+
+    #[test]
+    fn complete_searches_keep_the_table() {
+        let mut game = started_game(3, 3, 3);
+        play(&mut game, 1, 1);
+        let mut solver = Solver::new();
+        let mut rng = StdRng::seed_from_u64(7);
+        solver.best_move(&game, &mut rng);
+        let solved = solver.table.len();
+        play(&mut game, 0, 0);
+        play(&mut game, 0, 1);
+        solver.best_move(&game, &mut rng);
+        assert_eq!(solver.table.len(), solved);
+    }
+
+    #[test]
+    fn difficulty_from_str() {
+        for difficulty in [Difficulty::Easy, Difficulty::Medium, Difficulty::Hard].iter() {
+            assert_eq!(difficulty.to_string().parse(), Ok(*difficulty));
+        }
+        assert!("impossible".parse::<Difficulty>().is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// Number of bytes used by the big-endian length prefix of every frame.
+pub const HEADER_SIZE: usize = 4;
+
+/// Largest payload accepted by [`encode_frame`] and [`FrameDecoder`].
+/// Anything larger is treated as a corrupted or hostile stream.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Prefix the payload with its length so it can be recovered from a byte
+/// stream regardless of its content.
+pub fn encode_frame(payload: &[u8]) -> bincode::Result<Vec<u8>> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(Box::new(bincode::ErrorKind::SizeLimit));
+    }
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(payload);
+    Ok(bytes)
+}
+
+/// Streaming decoder that accumulates bytes from partial reads and yields
+/// complete frame payloads.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::with_max_frame_size(MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_frame_size,
+        }
+    }
+
+    /// Append freshly read bytes to the internal buffer.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// True if no bytes are buffered, not even part of a frame
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Pop the next complete payload, or `None` if more bytes are needed.
+    /// A frame announcing a size above the limit is an error and leaves the
+    /// decoder unusable since the stream can no longer be resynchronized.
+    pub fn next_frame(&mut self) -> bincode::Result<Option<Vec<u8>>> {
+        if self.buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&self.buffer[..HEADER_SIZE]);
+        let size = u32::from_be_bytes(header) as usize;
+        if size > self.max_frame_size {
+            return Err(Box::new(bincode::ErrorKind::SizeLimit));
+        }
+        if self.buffer.len() < HEADER_SIZE + size {
+            return Ok(None);
+        }
+        let payload = self.buffer[HEADER_SIZE..HEADER_SIZE + size].to_vec();
+        self.buffer.drain(..HEADER_SIZE + size);
+        Ok(Some(payload))
+    }
+}
+
+/// Block on the reader until one full frame is available.
+/// Returns `None` if the stream ends cleanly before a new frame starts.
+pub fn read_frame<R: Read>(r: &mut R) -> bincode::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_SIZE];
+    let mut filled = 0;
+    while filled < HEADER_SIZE {
+        let size = r.read(&mut header[filled..])?;
+        if size == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            return Err(Box::new(bincode::ErrorKind::Io(
+                std::io::ErrorKind::UnexpectedEof.into(),
+            )));
+        }
+        filled += size;
+    }
+    let size = u32::from_be_bytes(header) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(Box::new(bincode::ErrorKind::SizeLimit));
+    }
+    let mut payload = vec![0u8; size];
+    r.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_newline_bytes() {
+        let payload = vec![b'\n', 0, b'\n', b'\n', 255];
+        let frame = encode_frame(&payload).unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&frame);
+        assert_eq!(decoder.next_frame().unwrap(), Some(payload));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn partial_reads() {
+        let payloads = vec![vec![10u8; 10], vec![], vec![1, 2, 3]];
+        let bytes: Vec<u8> = payloads
+            .iter()
+            .flat_map(|p| encode_frame(p).unwrap())
+            .collect();
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        for byte in bytes {
+            decoder.extend(&[byte]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, payloads);
+    }
+
+    #[test]
+    fn multiple_frames_in_one_read() {
+        let mut bytes = encode_frame(b"a\nb").unwrap();
+        bytes.extend(encode_frame(b"\n").unwrap());
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+        assert_eq!(decoder.next_frame().unwrap(), Some(b"a\nb".to_vec()));
+        assert_eq!(decoder.next_frame().unwrap(), Some(b"\n".to_vec()));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_frames_rejected() {
+        assert!(encode_frame(&vec![0u8; MAX_FRAME_SIZE + 1]).is_err());
+
+        let mut decoder = FrameDecoder::with_max_frame_size(4);
+        decoder.extend(&encode_frame(&[0u8; 5]).unwrap());
+        assert!(decoder.next_frame().is_err());
+
+        let header = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        assert!(read_frame(&mut &header[..]).is_err());
+    }
+
+    #[test]
+    fn read_frame_from_reader() {
+        let mut bytes = encode_frame(&[10, 10]).unwrap();
+        bytes.extend(encode_frame(&[7]).unwrap());
+        let mut reader = &bytes[..];
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(vec![10, 10]));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(vec![7]));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+
+        let truncated = &encode_frame(&[1, 2, 3]).unwrap()[..5];
+        assert!(read_frame(&mut &truncated[..]).is_err());
+    }
+}
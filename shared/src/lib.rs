@@ -1,2 +1,9 @@
+pub mod ai;
+pub mod frame;
 pub mod message;
+pub mod rating;
+pub mod replay;
 pub mod serialize;
+pub mod sync;
+pub mod transport;
+pub mod websocket;
@@ -1,42 +1,273 @@
 use dipa::DiffPatch;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::rating::PlayerStats;
+use crate::replay::{Replay, ReplayId, ReplaySummary};
 
 pub type Username = String;
 pub type RoomName = String;
+pub type RoomKind = String;
+/// Handed out when joining the server, used to resume the session after the
+/// connection dropped
+pub type SessionToken = u64;
+/// Free-form options interpreted by the room kind, e.g. the board size
+pub type RoomOptions = HashMap<String, String>;
+
+pub const DEFAULT_ROOM_KIND: &str = "tic-tac-toe";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageFromServer {
     // Server related messages
-    ServerJoined,
+    ServerJoined(SessionToken),
     UsernameAlreadyTaken,
+    SessionResumed {
+        username: Username,
+        room: Option<RoomName>,
+    },
+    SessionNotFound,
+    // wrong password, unknown account or empty credentials
+    InvalidCredentials,
 
     // Room related messages
-    RoomJoined,
+    // carries the room name, which is generated by the server for matches
+    RoomJoined(RoomName),
     RoomFull,
     RoomDoesNotExist,
     RoomCreated,
     RoomNameAlreadyTaken,
+    RoomKindDoesNotExist,
+    RoomSpectated,
+    RoomList(Vec<RoomSummary>),
+
+    // Matchmaking messages
+    MatchQueued,
+    MatchCancelled,
+    MatchTimedOut,
+
+    // Rating messages
+    // sorted by rating, best first
+    Leaderboard(Vec<PlayerStats>),
+    PlayerStats(PlayerStats),
+    PlayerNotFound,
+
+    // Replay messages
+    Replay(Box<Replay>),
+    ReplayNotFound,
+    // newest first
+    ReplayList(Vec<ReplaySummary>),
 
     // Game state broadcast messages
-    // stores the serialized byte version of CreatedDelta<GameState>
-    // this is so that repeated cloning and serialization could be prevented
+    // both store serialized bytes so that the server does not need to know
+    // the concrete state type of a room, and so that repeated cloning and
+    // serialization could be prevented.
+    // StateChanged holds a CreatedDelta of the room state, State holds the
+    // full room state
     StateChanged(Vec<u8>),
-    State(GameState),
+    State(Vec<u8>),
+
+    // Sent only to the client whose game action was refused
+    ActionRejected(ActionRejection),
+
+    // Protocol misuse, the connection stays open
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+
+    // Limit messages, last so that the variants above keep their encoding
+    // the server does not take more clients
+    ServerFull,
+    // the server does not take more rooms
+    RoomLimitReached,
+}
+
+impl MessageFromServer {
+    pub fn error<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        MessageFromServer::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Error codes sent along with `MessageFromServer::Error`.
+/// They are serialized as their numeric value, so the numbers must never be
+/// reassigned. Codes unknown to this version decode as `Unknown`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "u16", into = "u16")]
+pub enum ErrorCode {
+    MalformedMessage,
+    NotJoinedServer,
+    AlreadyJoinedServer,
+    NotInRoom,
+    AlreadyInRoom,
+    AlreadyInQueue,
+    NotInQueue,
+    Unknown(u16),
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::MalformedMessage => 1,
+            ErrorCode::NotJoinedServer => 2,
+            ErrorCode::AlreadyJoinedServer => 3,
+            ErrorCode::NotInRoom => 4,
+            ErrorCode::AlreadyInRoom => 5,
+            ErrorCode::AlreadyInQueue => 6,
+            ErrorCode::NotInQueue => 7,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::MalformedMessage,
+            2 => ErrorCode::NotJoinedServer,
+            3 => ErrorCode::AlreadyJoinedServer,
+            4 => ErrorCode::NotInRoom,
+            5 => ErrorCode::AlreadyInRoom,
+            6 => ErrorCode::AlreadyInQueue,
+            7 => ErrorCode::NotInQueue,
+            code => ErrorCode::Unknown(code),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageFromClient {
     // Server related messages
-    JoinServer(Username), // -> ServerJoined or UsernameAlreadyTaken
-    LeaveServer,          // -> no response
+    // join as a guest, names of registered accounts are taken
+    // -> ServerJoined or UsernameAlreadyTaken or ServerFull
+    JoinServer(Username),
+    LeaveServer, // -> no response
+    // reattach to a dropped session within the grace period
+    Resume(SessionToken), // -> SessionResumed or SessionNotFound
+    // create an account and join the server with it
+    // -> ServerJoined or UsernameAlreadyTaken or InvalidCredentials or
+    // ServerFull
+    Register {
+        username: Username,
+        password: String,
+    },
+    // -> ServerJoined or InvalidCredentials or UsernameAlreadyTaken or
+    // ServerFull
+    Login {
+        username: Username,
+        password: String,
+    },
 
     // Room related messages
-    JoinRoom(RoomName),   // -> RoomJoined or RoomFull or RoomDoesNotExist
-    CreateRoom(RoomName), // -> RoomCreated or RoomNameAlreadyTaken
+    JoinRoom(RoomName), // -> RoomJoined or RoomFull or RoomDoesNotExist
+    // watch a room without taking a seat, never full
+    SpectateRoom(RoomName), // -> RoomSpectated or RoomDoesNotExist
+    // -> RoomCreated or RoomNameAlreadyTaken or RoomKindDoesNotExist or
+    // RoomLimitReached, an empty kind creates a room of the default kind of
+    // the server
+    CreateRoom {
+        name: RoomName,
+        kind: RoomKind,
+        options: RoomOptions,
+    },
     LeaveRoom,
+    ListRooms(RoomFilter), // -> RoomList
+
+    // Matchmaking messages
+    // wait for another player looking for the same room kind
+    // -> MatchQueued or RoomKindDoesNotExist, then RoomJoined or MatchTimedOut
+    // or RoomLimitReached, an empty kind waits for the default kind
+    FindMatch(RoomKind),
+    CancelMatch, // -> MatchCancelled
+
+    // Rating messages
+    // best rated players that finished at least one rated game
+    // -> Leaderboard
+    GetLeaderboard {
+        top_n: u32,
+    },
+    GetPlayerStats(Username), // -> PlayerStats or PlayerNotFound
+
+    // Replay messages
+    GetReplay(ReplayId), // -> Replay or ReplayNotFound
+    // games the player took part in -> ReplayList
+    ListReplays(Username),
 
     // Game-specific messages
-    GameAction(GameAction),
+    // stores the serialized room action (e.g. GameAction)
+    GameAction(Vec<u8>),
+}
+
+impl MessageFromClient {
+    pub fn game_action<A: Serialize>(action: &A) -> bincode::Result<Self> {
+        Ok(MessageFromClient::GameAction(bincode::serialize(action)?))
+    }
+
+    /// Name of the variant, safe to log unlike the credentials it may hold
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MessageFromClient::JoinServer(_) => "JoinServer",
+            MessageFromClient::LeaveServer => "LeaveServer",
+            MessageFromClient::Resume(_) => "Resume",
+            MessageFromClient::Register { .. } => "Register",
+            MessageFromClient::Login { .. } => "Login",
+            MessageFromClient::JoinRoom(_) => "JoinRoom",
+            MessageFromClient::SpectateRoom(_) => "SpectateRoom",
+            MessageFromClient::CreateRoom { .. } => "CreateRoom",
+            MessageFromClient::LeaveRoom => "LeaveRoom",
+            MessageFromClient::ListRooms(_) => "ListRooms",
+            MessageFromClient::FindMatch(_) => "FindMatch",
+            MessageFromClient::CancelMatch => "CancelMatch",
+            MessageFromClient::GetLeaderboard { .. } => "GetLeaderboard",
+            MessageFromClient::GetPlayerStats(_) => "GetPlayerStats",
+            MessageFromClient::GetReplay(_) => "GetReplay",
+            MessageFromClient::ListReplays(_) => "ListReplays",
+            MessageFromClient::GameAction(_) => "GameAction",
+        }
+    }
+}
+
+/// What a room reports about itself to the coordinator
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomStatus {
+    pub started: bool,
+    pub players: Vec<Username>,
+}
+
+/// A room as shown in the room list
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoomSummary {
+    pub name: RoomName,
+    pub kind: RoomKind,
+    // number of clients holding a seat
+    pub size: usize,
+    pub spectators: usize,
+    pub max_client: Option<usize>,
+    pub status: RoomStatus,
+}
+
+impl RoomSummary {
+    pub fn has_open_seat(&self) -> bool {
+        !matches!(self.max_client, Some(max) if self.size >= max)
+    }
+}
+
+/// Filters for `MessageFromClient::ListRooms`, the default lists every room
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RoomFilter {
+    pub open_only: bool,
+    pub kind: Option<RoomKind>,
+}
+
+impl RoomFilter {
+    pub fn matches(&self, room: &RoomSummary) -> bool {
+        (!self.open_only || room.has_open_seat())
+            && self.kind.as_ref().is_none_or(|kind| *kind == room.kind)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -45,79 +276,328 @@ pub enum GameAction {
     Restart,
 }
 
+/// Reason for the server refusing a game action. The game state is left
+/// untouched whenever an action is rejected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionRejection {
+    NotAPlayer,
+    NotStarted,
+    NotYourTurn,
+    GameOver,
+    GameNotOver,
+    OutOfBounds,
+    CellOccupied,
+    WaitingForReconnect,
+}
+
+impl fmt::Display for ActionRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ActionRejection::NotAPlayer => "You are not a player in this room!",
+            ActionRejection::NotStarted => "Still waiting for players!",
+            ActionRejection::NotYourTurn => "Not your turn!",
+            ActionRejection::GameOver => "The game is already over!",
+            ActionRejection::GameNotOver => "The game is not over yet!",
+            ActionRejection::OutOfBounds => "Invalid position(s)! Position is outside of the board",
+            ActionRejection::CellOccupied => "Invalid position!",
+            ActionRejection::WaitingForReconnect => "Waiting for a player to reconnect!",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// How much time the players of a room get for their moves
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeControl {
+    // every move has to be made within the same time
+    PerMove { seconds: u64 },
+    // a chess clock, the time left grows by the increment after every move
+    Clock { seconds: u64, increment: u64 },
+}
+
+impl TimeControl {
+    /// Milliseconds every player starts with
+    pub fn initial_millis(&self) -> u64 {
+        match self {
+            TimeControl::PerMove { seconds } | TimeControl::Clock { seconds, .. } => {
+                seconds.saturating_mul(1000)
+            }
+        }
+    }
+
+    /// Milliseconds left for a player that just moved with `time_left` remaining
+    pub fn after_move(&self, time_left: u64) -> u64 {
+        match self {
+            TimeControl::PerMove { seconds } => seconds.saturating_mul(1000),
+            TimeControl::Clock { increment, .. } => {
+                time_left.saturating_add(increment.saturating_mul(1000))
+            }
+        }
+    }
+}
+
+// one delta field per field, dipa-derive cannot batch this many fields
 #[derive(DiffPatch, Serialize, Deserialize, Debug, Clone)]
+#[dipa(field_batching_strategy = "no_batching")]
 pub struct GameState {
     pub players: Vec<String>,
+    // `height` rows of `width` cells, indexed as board[x][y] where x is the row
     pub board: Vec<Vec<i8>>,
     pub winner: Option<i8>,
     pub turn: u8,
     pub started: bool,
+    // dipa cannot diff `usize`, see the getters
+    width: u8,
+    height: u8,
+    // number of nodes in a row needed to win
+    win_length: u8,
+    // players whose connection dropped, the game is paused until they are
+    // back or their seat is given up
+    pub disconnected: Vec<String>,
+    // milliseconds left for each player, empty if the room has no time control
+    pub time_left: Vec<u64>,
+    // player that ran out of time and lost
+    pub timed_out: Option<i8>,
 }
 
 impl Default for GameState {
     fn default() -> Self {
-        let board = (0..3).map(|_| (0..3).map(|_| -1).collect()).collect();
+        Self::new(3, 3, 3)
+    }
+}
+
+impl GameState {
+    /// Create the state of an m,n,k-game: a `width` x `height` board where
+    /// `win_length` nodes in a row (horizontally, vertically or diagonally) win.
+    /// None of them can exceed 255.
+    pub fn new(width: usize, height: usize, win_length: usize) -> Self {
+        let size = |value: usize| u8::try_from(value).expect("board size exceeds 255");
         Self {
             players: Vec::new(),
-            board,
+            board: empty_board(width, height),
             winner: None,
             turn: 0,
             started: false,
+            width: size(width),
+            height: size(height),
+            win_length: size(win_length),
+            disconnected: Vec::new(),
+            time_left: Vec::new(),
+            timed_out: None,
         }
     }
-}
 
-impl GameState {
+    pub fn width(&self) -> usize {
+        self.width as usize
+    }
+
+    pub fn height(&self) -> usize {
+        self.height as usize
+    }
+
+    pub fn win_length(&self) -> usize {
+        self.win_length as usize
+    }
+
     pub fn reset_game(&mut self) {
         self.winner = None;
-        self.board = (0..3).map(|_| (0..3).map(|_| -1).collect()).collect();
+        self.timed_out = None;
+        self.board = empty_board(self.width(), self.height());
         self.turn = 0;
     }
 
+    /// Check that the given username is allowed to place a node at x y
+    pub fn validate_place_node(
+        &self,
+        x: usize,
+        y: usize,
+        username: &Username,
+    ) -> Result<(), ActionRejection> {
+        let player = self.player_index(username)?;
+        if !self.started {
+            Err(ActionRejection::NotStarted)
+        } else if !self.disconnected.is_empty() {
+            Err(ActionRejection::WaitingForReconnect)
+        } else if self.is_gameover() {
+            Err(ActionRejection::GameOver)
+        } else if player != self.turn as usize {
+            Err(ActionRejection::NotYourTurn)
+        } else if x >= self.height() || y >= self.width() {
+            Err(ActionRejection::OutOfBounds)
+        } else if self.board[x][y] != -1 {
+            Err(ActionRejection::CellOccupied)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that the given username is allowed to restart the game
+    pub fn validate_restart(&self, username: &Username) -> Result<(), ActionRejection> {
+        self.player_index(username)?;
+        if !self.started {
+            Err(ActionRejection::NotStarted)
+        } else if !self.disconnected.is_empty() {
+            Err(ActionRejection::WaitingForReconnect)
+        } else if !self.is_gameover() {
+            Err(ActionRejection::GameNotOver)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn player_index(&self, username: &Username) -> Result<usize, ActionRejection> {
+        self.players
+            .iter()
+            .position(|p| p == username)
+            .ok_or(ActionRejection::NotAPlayer)
+    }
+
+    /// Give every player the initial time of the time control
+    pub fn start_clocks(&mut self, time_control: &TimeControl) {
+        self.time_left = vec![time_control.initial_millis(); self.players.len()];
+    }
+
+    /// Run the clock of the player whose turn it is, the clocks stop while
+    /// the game is not running. Returns true if the player ran out of time,
+    /// the other player wins in that case.
+    pub fn run_clock(&mut self, elapsed_millis: u64) -> bool {
+        if !self.started || self.is_gameover() || !self.disconnected.is_empty() {
+            return false;
+        }
+        let time_left = match self.time_left.get_mut(self.turn as usize) {
+            Some(time_left) => time_left,
+            None => return false,
+        };
+        *time_left = time_left.saturating_sub(elapsed_millis);
+        if *time_left > 0 {
+            return false;
+        }
+        self.timed_out = Some(self.turn as i8);
+        self.winner = Some((self.turn == 0) as i8);
+        true
+    }
+
     /// Place node at given x y coordinate by the given username
     /// The winner will be automatically checked and updated
     pub fn place_node(&mut self, x: usize, y: usize, username: &Username) {
         if self.board[x][y] == -1 {
             self.board[x][y] = (self.players[1] == *username) as i8;
-            self.winner = self.check_winner(x, y);
+            if self.winner.is_none() {
+                self.winner = self.check_winner(x, y);
+            }
         }
     }
 
-    fn check_winner(&mut self, x: usize, y: usize) -> Option<i8> {
+    /// Count the nodes in a row through x y along each of the four
+    /// directions and report the color at x y if any line is long enough
+    pub(crate) fn check_winner(&self, x: usize, y: usize) -> Option<i8> {
         let color = self.board[x][y];
-        if self.board[0][y] == self.board[1][y] && self.board[1][y] == self.board[2][y] {
-            Some(color)
-        } else if self.board[x][0] == self.board[x][1] && self.board[x][1] == self.board[x][2] {
-            Some(color)
-        } else {
-            if self.board[0][0] == self.board[1][1]
-                && self.board[1][1] == self.board[2][2]
-                && self.board[1][1] == color
-            {
-                Some(color)
-            } else if self.board[0][2] == self.board[1][1]
-                && self.board[1][1] == self.board[2][0]
-                && self.board[1][1] == color
-            {
-                Some(color)
-            } else {
-                None
+        if color == -1 {
+            return None;
+        }
+        let directions = [(0, 1), (1, 0), (1, 1), (1, -1)];
+        directions
+            .iter()
+            .any(|&(dx, dy)| {
+                1 + self.count_in_direction(x, y, dx, dy, color)
+                    + self.count_in_direction(x, y, -dx, -dy, color)
+                    >= self.win_length()
+            })
+            .then_some(color)
+    }
+
+    /// Number of consecutive nodes of the given color starting next to x y
+    fn count_in_direction(&self, x: usize, y: usize, dx: isize, dy: isize, color: i8) -> usize {
+        let mut count = 0;
+        let (mut x, mut y) = (x as isize, y as isize);
+        loop {
+            x += dx;
+            y += dy;
+            if x < 0 || y < 0 || x >= self.height() as isize || y >= self.width() as isize {
+                return count;
+            }
+            if self.board[x as usize][y as usize] != color {
+                return count;
             }
+            count += 1;
         }
     }
 
+    /// The game is over once someone won or the board is full
     pub fn is_gameover(&self) -> bool {
-        for row in &self.board {
-            for block in row {
-                if *block == -1 {
-                    return false;
+        self.winner.is_some() || self.is_board_full()
+    }
+
+    pub fn is_board_full(&self) -> bool {
+        self.board.iter().flatten().all(|block| *block != -1)
+    }
+
+    /// Evaluate the whole board, independently of the incrementally
+    /// maintained `winner`
+    pub fn outcome(&self) -> Outcome {
+        // a time out is the only outcome that cannot be read from the board
+        if let Some(player) = self.timed_out {
+            return Outcome::TimedOut { player };
+        }
+        let directions = [(0, 1), (1, 0), (1, 1), (1, -1)];
+        for x in 0..self.height() {
+            for y in 0..self.width() {
+                let color = self.board[x][y];
+                if color == -1 {
+                    continue;
+                }
+                for &(dx, dy) in directions.iter() {
+                    // only start counting from the first node of a line
+                    if self.count_in_direction(x, y, -dx, -dy, color) > 0 {
+                        continue;
+                    }
+                    let length = 1 + self.count_in_direction(x, y, dx, dy, color);
+                    if length >= self.win_length() {
+                        let line = (0..length as isize)
+                            .map(|i| {
+                                (
+                                    (x as isize + i * dx) as usize,
+                                    (y as isize + i * dy) as usize,
+                                )
+                            })
+                            .collect();
+                        return Outcome::Win {
+                            player: color,
+                            line,
+                        };
+                    }
                 }
             }
         }
-        return true;
+        if self.is_board_full() {
+            Outcome::Draw
+        } else {
+            Outcome::InProgress
+        }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    InProgress,
+    // `line` holds the x y coordinates of every node in the winning line
+    Win {
+        player: i8,
+        line: Vec<(usize, usize)>,
+    },
+    Draw,
+    // `player` ran out of time and lost
+    TimedOut {
+        player: i8,
+    },
+}
+
+fn empty_board(width: usize, height: usize) -> Vec<Vec<i8>> {
+    (0..height)
+        .map(|_| (0..width).map(|_| -1).collect())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,7 +609,7 @@ mod tests {
         assert_eq!(game.players.len(), 0);
         assert_eq!(game.board, [[-1, -1, -1], [-1, -1, -1], [-1, -1, -1]]);
         assert_eq!(game.turn, 0);
-        assert_eq!(game.started, false);
+        assert!(!game.started);
         assert_eq!(game.winner, None);
     }
 
@@ -189,4 +669,345 @@ mod tests {
             game.reset_game();
         }
     }
+
+    fn started_game() -> GameState {
+        let mut game = GameState::default();
+        game.players.extend(PLAYERS.iter().map(|s| s.to_string()));
+        game.started = true;
+        game
+    }
+
+    #[test]
+    fn reject_invalid_moves() {
+        let a = PLAYERS[0].to_string();
+        let b = PLAYERS[1].to_string();
+
+        let mut game = started_game();
+        game.started = false;
+        assert_eq!(
+            game.validate_place_node(0, 0, &a),
+            Err(ActionRejection::NotStarted)
+        );
+
+        let mut game = started_game();
+        assert_eq!(
+            game.validate_place_node(0, 0, &"stranger".to_string()),
+            Err(ActionRejection::NotAPlayer)
+        );
+        assert_eq!(
+            game.validate_place_node(0, 0, &b),
+            Err(ActionRejection::NotYourTurn)
+        );
+        assert_eq!(
+            game.validate_place_node(3, 0, &a),
+            Err(ActionRejection::OutOfBounds)
+        );
+        assert_eq!(
+            game.validate_place_node(0, usize::MAX, &a),
+            Err(ActionRejection::OutOfBounds)
+        );
+        assert_eq!(game.validate_place_node(1, 1, &a), Ok(()));
+
+        game.place_node(1, 1, &a);
+        game.turn = 1;
+        assert_eq!(
+            game.validate_place_node(1, 1, &b),
+            Err(ActionRejection::CellOccupied)
+        );
+        assert_eq!(game.validate_restart(&b), Err(ActionRejection::GameNotOver));
+
+        game.winner = Some(0);
+        assert_eq!(
+            game.validate_place_node(0, 0, &b),
+            Err(ActionRejection::GameOver)
+        );
+        assert_eq!(game.validate_restart(&b), Ok(()));
+
+        game.disconnected.push(a.clone());
+        assert_eq!(
+            game.validate_restart(&b),
+            Err(ActionRejection::WaitingForReconnect)
+        );
+        game.winner = None;
+        assert_eq!(
+            game.validate_place_node(0, 0, &b),
+            Err(ActionRejection::WaitingForReconnect)
+        );
+    }
+
+    #[test]
+    fn error_codes_are_stable() {
+        let codes = [
+            (ErrorCode::MalformedMessage, 1u16),
+            (ErrorCode::NotJoinedServer, 2),
+            (ErrorCode::AlreadyJoinedServer, 3),
+            (ErrorCode::NotInRoom, 4),
+            (ErrorCode::AlreadyInRoom, 5),
+            (ErrorCode::AlreadyInQueue, 6),
+            (ErrorCode::NotInQueue, 7),
+        ];
+        for (code, value) in codes.iter() {
+            assert_eq!(u16::from(*code), *value);
+            assert_eq!(ErrorCode::from(*value), *code);
+            let bytes = bincode::serialize(code).unwrap();
+            assert_eq!(bytes, bincode::serialize(value).unwrap());
+            assert_eq!(bincode::deserialize::<ErrorCode>(&bytes).unwrap(), *code);
+        }
+        assert_eq!(ErrorCode::from(9999), ErrorCode::Unknown(9999));
+    }
+
+    #[test]
+    fn limit_messages_keep_the_encoding_of_older_variants() {
+        // bincode encodes the variant index first
+        let index = |msg: &MessageFromServer| {
+            let bytes = bincode::serialize(msg).unwrap();
+            bincode::deserialize::<u32>(&bytes).unwrap()
+        };
+        let last_older = index(&MessageFromServer::error(ErrorCode::NotInRoom, ""));
+        assert_eq!(index(&MessageFromServer::ServerFull), last_older + 1);
+        assert_eq!(index(&MessageFromServer::RoomLimitReached), last_older + 2);
+    }
+
+    #[test]
+    fn gomoku_win() {
+        let mut game = GameState::new(15, 15, 5);
+        game.players.extend(PLAYERS.iter().map(|s| s.to_string()));
+        let player = PLAYERS[0].to_string();
+        assert_eq!(game.board.len(), 15);
+        assert!(game.board.iter().all(|row| row.len() == 15));
+
+        // anti-diagonal, finishing in the middle of the line
+        for (x, y) in [(14, 0), (13, 1), (11, 3), (10, 4)].iter() {
+            game.place_node(*x, *y, &player);
+            assert!(game.winner.is_none());
+        }
+        game.place_node(12, 2, &player);
+        assert_eq!(game.winner, Some(0));
+    }
+
+    #[test]
+    fn rectangular_board() {
+        let mut game = GameState::new(5, 2, 4);
+        game.players.extend(PLAYERS.iter().map(|s| s.to_string()));
+        let player = PLAYERS[1].to_string();
+        assert_eq!(game.board.len(), 2);
+        assert!(game.board.iter().all(|row| row.len() == 5));
+
+        // the column is too short for a vertical win
+        game.place_node(0, 4, &player);
+        game.place_node(1, 4, &player);
+        assert!(game.winner.is_none());
+        for y in 1..3 {
+            game.place_node(1, y, &player);
+            assert!(game.winner.is_none());
+        }
+        game.place_node(1, 3, &player);
+        assert_eq!(game.winner, Some(1));
+    }
+
+    #[test]
+    fn no_winner_from_empty_cells() {
+        let mut game = GameState::default();
+        game.players.extend(PLAYERS.iter().map(|s| s.to_string()));
+        // the rest of the row, column and diagonals are still empty
+        game.place_node(0, 0, &PLAYERS[0].to_string());
+        assert_eq!(game.winner, None);
+        assert_eq!(game.outcome(), Outcome::InProgress);
+    }
+
+    #[test]
+    fn win_on_full_board() {
+        let mut game = GameState::default();
+        game.players.extend(PLAYERS.iter().map(|s| s.to_string()));
+        // X O X
+        // O X O
+        // O X X  <- X completes the diagonal with the last move
+        let moves = [
+            (0, 0),
+            (0, 1),
+            (0, 2),
+            (1, 0),
+            (1, 1),
+            (1, 2),
+            (2, 1),
+            (2, 0),
+            (2, 2),
+        ];
+        for (i, (x, y)) in moves.iter().enumerate() {
+            game.place_node(*x, *y, &PLAYERS[i % 2].to_string());
+        }
+        assert!(game.is_board_full());
+        assert_eq!(game.winner, Some(0));
+        assert_eq!(
+            game.outcome(),
+            Outcome::Win {
+                player: 0,
+                line: vec![(0, 0), (1, 1), (2, 2)]
+            }
+        );
+    }
+
+    /// Reference evaluation of a 3x3 board listing every line explicitly
+    fn reference_outcome(board: &[Vec<i8>]) -> (Option<i8>, Vec<Vec<(usize, usize)>>) {
+        let mut lines: Vec<Vec<(usize, usize)>> = Vec::new();
+        for i in 0..3 {
+            lines.push((0..3).map(|j| (i, j)).collect());
+            lines.push((0..3).map(|j| (j, i)).collect());
+        }
+        lines.push((0..3).map(|i| (i, i)).collect());
+        lines.push((0..3).map(|i| (i, 2 - i)).collect());
+
+        let winning: Vec<_> = lines
+            .into_iter()
+            .filter(|line| {
+                let (x, y) = line[0];
+                board[x][y] != -1 && line.iter().all(|&(i, j)| board[i][j] == board[x][y])
+            })
+            .collect();
+        let winner = winning.first().map(|line| board[line[0].0][line[0].1]);
+        (winner, winning)
+    }
+
+    fn explore(
+        game: &GameState,
+        seen: &mut std::collections::HashSet<Vec<Vec<i8>>>,
+        terminal: &mut [usize; 3],
+    ) {
+        if !seen.insert(game.board.clone()) {
+            return;
+        }
+
+        let (winner, winning_lines) = reference_outcome(&game.board);
+        assert_eq!(game.winner, winner, "{:?}", game.board);
+        match game.outcome() {
+            Outcome::Win { player, line } => {
+                assert_eq!(Some(player), winner);
+                assert!(winning_lines.contains(&line), "{:?}", game.board);
+                assert!(game.is_gameover());
+                terminal[player as usize] += 1;
+                return;
+            }
+            Outcome::Draw => {
+                assert_eq!(winner, None);
+                assert!(game.is_board_full() && game.is_gameover());
+                terminal[2] += 1;
+                return;
+            }
+            Outcome::InProgress => {
+                assert_eq!(winner, None);
+                assert!(!game.is_gameover());
+            }
+            Outcome::TimedOut { .. } => unreachable!(),
+        }
+
+        let player = game.players[game.turn as usize].clone();
+        for x in 0..3 {
+            for y in 0..3 {
+                if game.validate_place_node(x, y, &player).is_ok() {
+                    let mut next = game.clone();
+                    next.place_node(x, y, &player);
+                    next.turn = (next.turn == 0) as u8;
+                    explore(&next, seen, terminal);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_reachable_position() {
+        let mut game = GameState::default();
+        game.players.extend(PLAYERS.iter().map(|s| s.to_string()));
+        game.started = true;
+
+        let mut seen = std::collections::HashSet::new();
+        // X wins, O wins, draws
+        let mut terminal = [0; 3];
+        explore(&game, &mut seen, &mut terminal);
+
+        assert_eq!(seen.len(), 5478);
+        assert_eq!(terminal, [626, 316, 16]);
+    }
+
+    #[test]
+    fn room_filter() {
+        let room = |name: &str, kind: &str, size, max_client| RoomSummary {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            size,
+            spectators: 0,
+            max_client,
+            status: RoomStatus::default(),
+        };
+        let rooms = [
+            room("a", DEFAULT_ROOM_KIND, 1, Some(2)),
+            room("b", DEFAULT_ROOM_KIND, 2, Some(2)),
+            room("c", "gomoku", 1, Some(1)),
+            room("d", "gomoku", 5, None),
+        ];
+        let names = |filter: RoomFilter| -> Vec<_> {
+            rooms
+                .iter()
+                .filter(|room| filter.matches(room))
+                .map(|room| room.name.as_str())
+                .collect()
+        };
+
+        assert_eq!(names(RoomFilter::default()), ["a", "b", "c", "d"]);
+        let open_only = RoomFilter {
+            open_only: true,
+            kind: None,
+        };
+        assert_eq!(names(open_only), ["a", "d"]);
+        let gomoku = RoomFilter {
+            open_only: false,
+            kind: Some("gomoku".to_string()),
+        };
+        assert_eq!(names(gomoku), ["c", "d"]);
+        let open_gomoku = RoomFilter {
+            open_only: true,
+            kind: Some("gomoku".to_string()),
+        };
+        assert_eq!(names(open_gomoku), ["d"]);
+    }
+
+    #[test]
+    fn time_controls() {
+        let a = PLAYERS[0].to_string();
+        let per_move = TimeControl::PerMove { seconds: 10 };
+        let clock = TimeControl::Clock {
+            seconds: 60,
+            increment: 2,
+        };
+        assert_eq!(per_move.after_move(1), 10_000);
+        assert_eq!(clock.after_move(1), 2_001);
+        let endless = TimeControl::Clock {
+            seconds: u64::MAX,
+            increment: u64::MAX,
+        };
+        assert_eq!(endless.initial_millis(), u64::MAX);
+        assert_eq!(endless.after_move(1), u64::MAX);
+
+        let mut game = started_game();
+        assert!(!game.run_clock(1_000_000));
+
+        game.start_clocks(&per_move);
+        assert_eq!(game.time_left, [10_000, 10_000]);
+        assert!(!game.run_clock(4_000));
+        assert_eq!(game.time_left, [6_000, 10_000]);
+
+        // the clocks stop while a player is disconnected
+        game.disconnected.push(a);
+        assert!(!game.run_clock(10_000));
+        game.disconnected.clear();
+
+        assert!(game.run_clock(6_000));
+        assert_eq!(game.time_left, [0, 10_000]);
+        assert_eq!(game.winner, Some(1));
+        assert!(game.is_gameover());
+        assert_eq!(game.outcome(), Outcome::TimedOut { player: 0 });
+        assert!(!game.run_clock(1_000));
+
+        game.reset_game();
+        assert_eq!(game.outcome(), Outcome::InProgress);
+    }
 }
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+use crate::message::Username;
+
+/// Rating of players that have not finished a game yet
+pub const INITIAL_RATING: f64 = 1500.0;
+
+/// Largest change of a rating after a single game
+const K_FACTOR: f64 = 32.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum GameResult {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl GameResult {
+    fn score(self) -> f64 {
+        match self {
+            GameResult::Win => 1.0,
+            GameResult::Loss => 0.0,
+            GameResult::Draw => 0.5,
+        }
+    }
+}
+
+/// Elo rating and record of a player
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerStats {
+    pub username: Username,
+    pub rating: f64,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+impl PlayerStats {
+    pub fn new(username: Username) -> Self {
+        Self {
+            username,
+            rating: INITIAL_RATING,
+            wins: 0,
+            losses: 0,
+            draws: 0,
+        }
+    }
+
+    pub fn games(&self) -> u32 {
+        self.wins + self.losses + self.draws
+    }
+
+    /// Update the rating and the record after a game against an opponent
+    /// with the given rating before the game
+    pub fn record(&mut self, opponent_rating: f64, result: GameResult) {
+        self.rating += K_FACTOR * (result.score() - expected_score(self.rating, opponent_rating));
+        match result {
+            GameResult::Win => self.wins += 1,
+            GameResult::Loss => self.losses += 1,
+            GameResult::Draw => self.draws += 1,
+        }
+    }
+}
+
+/// Probability of a player with `rating` beating an opponent with
+/// `opponent_rating`, counting draws as half a win
+pub fn expected_score(rating: f64, opponent_rating: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent_rating - rating) / 400.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(first: &mut PlayerStats, second: &mut PlayerStats, result: GameResult) {
+        let (first_rating, second_rating) = (first.rating, second.rating);
+        first.record(second_rating, result);
+        second.record(
+            first_rating,
+            match result {
+                GameResult::Win => GameResult::Loss,
+                GameResult::Loss => GameResult::Win,
+                GameResult::Draw => GameResult::Draw,
+            },
+        );
+    }
+
+    #[test]
+    fn equal_players() {
+        let mut alice = PlayerStats::new("alice".to_string());
+        let mut bob = PlayerStats::new("bob".to_string());
+        assert_eq!(expected_score(alice.rating, bob.rating), 0.5);
+
+        play(&mut alice, &mut bob, GameResult::Draw);
+        assert_eq!(alice.rating, INITIAL_RATING);
+        assert_eq!(bob.rating, INITIAL_RATING);
+
+        play(&mut alice, &mut bob, GameResult::Win);
+        assert_eq!(alice.rating, INITIAL_RATING + K_FACTOR / 2.0);
+        assert_eq!(bob.rating, INITIAL_RATING - K_FACTOR / 2.0);
+
+        assert_eq!((alice.wins, alice.losses, alice.draws), (1, 0, 1));
+        assert_eq!((bob.wins, bob.losses, bob.draws), (0, 1, 1));
+        assert_eq!(alice.games(), 2);
+    }
+
+    #[test]
+    fn upsets_move_ratings_more() {
+        let mut favourite = PlayerStats::new("favourite".to_string());
+        favourite.rating = 1800.0;
+        let mut underdog = PlayerStats::new("underdog".to_string());
+        assert!(expected_score(favourite.rating, underdog.rating) > 0.5);
+
+        let mut expected = (favourite.clone(), underdog.clone());
+        play(&mut expected.0, &mut expected.1, GameResult::Win);
+        let gain = expected.0.rating - favourite.rating;
+
+        let total = favourite.rating + underdog.rating;
+        play(&mut favourite, &mut underdog, GameResult::Loss);
+        assert!(underdog.rating - INITIAL_RATING > gain);
+        // points move from one player to the other
+        assert!((favourite.rating + underdog.rating - total).abs() < 1e-9);
+    }
+}
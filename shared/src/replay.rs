@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+
+use crate::message::{GameState, Username};
+
+pub type ReplayId = u64;
+
+/// Version written in front of every encoded replay, bump it whenever
+/// `Replay` changes and keep decoding the older versions
+pub const REPLAY_VERSION: u16 = 1;
+
+/// Number of bytes used by the little-endian version prefix
+const VERSION_SIZE: usize = 2;
+
+/// A finished game with every move in the order it was played
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Replay {
+    pub id: ReplayId,
+    pub room_name: String,
+    pub players: Vec<Username>,
+    pub width: usize,
+    pub height: usize,
+    pub win_length: usize,
+    // seconds since the unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub moves: Vec<(usize, usize)>,
+    pub winner: Option<i8>,
+    // player that ran out of time, the game ended without a last move
+    pub timed_out: Option<i8>,
+}
+
+/// Entry of a replay listing, without the moves
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplaySummary {
+    pub id: ReplayId,
+    pub room_name: String,
+    pub players: Vec<Username>,
+    pub finished_at: u64,
+    pub winner: Option<i8>,
+}
+
+impl Replay {
+    pub fn summary(&self) -> ReplaySummary {
+        ReplaySummary {
+            id: self.id,
+            room_name: self.room_name.clone(),
+            players: self.players.clone(),
+            finished_at: self.finished_at,
+            winner: self.winner,
+        }
+    }
+
+    /// State of the game after the first `moves` moves, the final state
+    /// includes a time out
+    pub fn state_after(&self, moves: usize) -> GameState {
+        let mut state = GameState::new(self.width, self.height, self.win_length);
+        state.players = self.players.clone();
+        state.started = true;
+        for &(x, y) in self.moves.iter().take(moves) {
+            let player = state.players[state.turn as usize].clone();
+            state.place_node(x, y, &player);
+            state.turn = (state.turn == 0) as u8;
+        }
+        if moves >= self.moves.len() {
+            if let Some(player) = self.timed_out {
+                state.timed_out = Some(player);
+                state.winner = self.winner;
+            }
+        }
+        state
+    }
+}
+
+/// Serialize the replay behind its format version
+pub fn encode_replay(replay: &Replay) -> bincode::Result<Vec<u8>> {
+    let mut bytes = REPLAY_VERSION.to_le_bytes().to_vec();
+    bincode::serialize_into(&mut bytes, replay)?;
+    Ok(bytes)
+}
+
+/// Read a replay written by `encode_replay` of this or an older version
+pub fn decode_replay(bytes: &[u8]) -> bincode::Result<Replay> {
+    if bytes.len() < VERSION_SIZE {
+        return Err(Box::new(bincode::ErrorKind::Custom(
+            "replay is missing its version".to_string(),
+        )));
+    }
+    let (version, payload) = bytes.split_at(VERSION_SIZE);
+    match u16::from_le_bytes([version[0], version[1]]) {
+        1 => bincode::deserialize(payload),
+        version => Err(Box::new(bincode::ErrorKind::Custom(format!(
+            "unsupported replay version {}",
+            version
+        )))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Outcome;
+
+    fn replay() -> Replay {
+        Replay {
+            id: 7,
+            room_name: "room".to_string(),
+            players: vec!["a".to_string(), "b".to_string()],
+            width: 3,
+            height: 3,
+            win_length: 3,
+            started_at: 1_600_000_000,
+            finished_at: 1_600_000_060,
+            moves: vec![(0, 0), (1, 0), (0, 1), (1, 1), (0, 2)],
+            winner: Some(0),
+            timed_out: None,
+        }
+    }
+
+    #[test]
+    fn versioned_format() {
+        let replay = replay();
+        let bytes = encode_replay(&replay).unwrap();
+        assert_eq!(bytes[..VERSION_SIZE], REPLAY_VERSION.to_le_bytes());
+        assert_eq!(decode_replay(&bytes).unwrap(), replay);
+
+        let mut future = bytes.clone();
+        future[..VERSION_SIZE].copy_from_slice(&(REPLAY_VERSION + 1).to_le_bytes());
+        assert!(decode_replay(&future).is_err());
+        assert!(decode_replay(&bytes[..1]).is_err());
+        assert!(decode_replay(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn step_through_moves() {
+        let replay = replay();
+        let start = replay.state_after(0);
+        assert!(start.board.iter().flatten().all(|&node| node == -1));
+        assert_eq!(start.outcome(), Outcome::InProgress);
+
+        let state = replay.state_after(2);
+        assert_eq!(state.board[0][0], 0);
+        assert_eq!(state.board[1][0], 1);
+        assert_eq!(state.turn, 0);
+
+        let state = replay.state_after(replay.moves.len());
+        assert_eq!(state.winner, replay.winner);
+        assert!(matches!(state.outcome(), Outcome::Win { player: 0, .. }));
+    }
+
+    #[test]
+    fn time_out_is_replayed_last() {
+        let mut replay = replay();
+        replay.moves.truncate(3);
+        replay.winner = Some(0);
+        replay.timed_out = Some(1);
+
+        assert_eq!(replay.state_after(3).timed_out, Some(1));
+        assert_eq!(replay.state_after(2).timed_out, None);
+        assert_eq!(
+            replay.state_after(3).outcome(),
+            Outcome::TimedOut { player: 1 }
+        );
+    }
+}
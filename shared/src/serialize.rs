@@ -1,32 +1,48 @@
+use crate::frame::{encode_frame, FrameDecoder};
 use bincode;
 use serde::{Deserialize, Serialize};
 use std::io::Write;
 
-/// serialize and write the given object with a trailing newline character.
+/// serialize and write the given object as a length-prefixed frame.
 pub fn write_serialized<S, W>(s: S, w: &mut W) -> bincode::Result<()>
 where
     S: Serialize,
     W: Write,
 {
-    w.write(&serialize(&s)?)?;
+    w.write_all(&serialize(&s)?)?;
     Ok(())
 }
 
+/// serialize the given object into a length-prefixed frame.
 pub fn serialize<S: Serialize>(s: S) -> bincode::Result<Vec<u8>> {
-    let mut bytes = bincode::serialize(&s)?;
-    bytes.append(&mut vec!['\n' as u8]);
-    Ok(bytes)
+    let bytes = bincode::serialize(&s)?;
+    encode_frame(&bytes)
 }
 
-/// deserialize object from a byte array with a trailing newline character.
-pub fn read_serialized<'de, D>(bytes: &'de [u8]) -> bincode::Result<D>
+/// deserialize object from a byte array holding exactly one length-prefixed frame.
+pub fn read_serialized<D>(bytes: &[u8]) -> bincode::Result<D>
 where
-    D: Deserialize<'de>,
+    D: for<'de> Deserialize<'de>,
 {
-    let result = deserialize(bytes)?;
-    Ok(result)
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(bytes);
+    let frame = match decoder.next_frame()? {
+        Some(frame) => frame,
+        None => {
+            return Err(Box::new(bincode::ErrorKind::Io(
+                std::io::ErrorKind::UnexpectedEof.into(),
+            )))
+        }
+    };
+    if !decoder.is_empty() {
+        return Err(Box::new(bincode::ErrorKind::Custom(
+            "trailing bytes after the frame".to_string(),
+        )));
+    }
+    deserialize(&frame)
 }
 
+/// deserialize object from an unframed bincode payload.
 pub fn deserialize<'de, D>(bytes: &'de [u8]) -> bincode::Result<D>
 where
     D: Deserialize<'de>,
@@ -55,4 +71,30 @@ mod tests {
         println!("{:?}", buffer);
         assert_eq!(original, reconstructed);
     }
+
+    #[test]
+    fn payload_containing_newline() {
+        let original = TestStruct {
+            a: '\n' as usize,
+            b: false,
+        };
+        let bytes = serialize(original.clone()).expect("serialize failed");
+        assert!(bytes.contains(&b'\n'));
+        let reconstructed: TestStruct = read_serialized(&bytes).expect("read_serialized failed");
+        assert_eq!(original, reconstructed);
+    }
+
+    #[test]
+    fn reject_trailing_bytes() {
+        let original = TestStruct { a: 1, b: true };
+        let mut bytes = serialize(original.clone()).expect("serialize failed");
+        let frame_len = bytes.len();
+        bytes.push(0);
+        assert!(read_serialized::<TestStruct>(&bytes).is_err());
+
+        // a second frame is not allowed either
+        bytes.truncate(frame_len);
+        bytes.extend(serialize(original).expect("serialize failed"));
+        assert!(read_serialized::<TestStruct>(&bytes).is_err());
+    }
 }
@@ -0,0 +1,304 @@
+use dipa::{Diffable, Patchable};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::serialize::deserialize;
+
+/// Delta between two states as received by a client
+pub type DeltaOwned<S> = <S as Diffable<'static, 'static, S>>::DeltaOwned;
+
+/// State that can be sent as a full copy or as a dipa delta
+pub trait SyncState: Clone + Serialize + for<'s, 'e> Diffable<'s, 'e, Self> {
+    /// Serialized delta that turns `self` into `next`, `None` if the delta
+    /// would not reproduce `next`
+    fn serialize_delta(&self, next: &Self) -> bincode::Result<Option<Vec<u8>>>;
+}
+
+impl<S> SyncState for S
+where
+    S: Clone + Serialize + for<'s, 'e> Diffable<'s, 'e, S> + Patchable<DeltaOwned<S>>,
+    for<'s, 'e> <S as Diffable<'s, 'e, S>>::Delta: Serialize,
+    DeltaOwned<S>: DeserializeOwned,
+{
+    fn serialize_delta(&self, next: &Self) -> bincode::Result<Option<Vec<u8>>> {
+        let delta = bincode::serialize(&self.create_delta_towards(next).delta)?;
+        // the sequence diff of dipa 0.1 drops the changes of a `Vec` that
+        // keeps none of its elements, e.g. the board of a restarted game
+        let mut patched = self.clone();
+        apply_delta(&mut patched, &delta)?;
+        if bincode::serialize(&patched)? == bincode::serialize(next)? {
+            Ok(Some(delta))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Last state broadcast to the clients of a room.
+/// Clients that already hold a copy of the checkpoint only need the delta
+/// towards the next state, everyone else needs the full state.
+#[derive(Debug)]
+pub struct StateCheckpoint<S> {
+    state: Option<S>,
+}
+
+impl<S> Default for StateCheckpoint<S> {
+    fn default() -> Self {
+        Self { state: None }
+    }
+}
+
+impl<S: SyncState> StateCheckpoint<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the checkpoint to the given state.
+    /// Returns the serialized delta from the previous checkpoint, or `None`
+    /// if there was no checkpoint to diff against or the delta cannot
+    /// express the change, every client needs the full state then.
+    pub fn advance(&mut self, state: &S) -> bincode::Result<Option<Vec<u8>>> {
+        let delta = match &self.state {
+            Some(prev_state) => prev_state.serialize_delta(state)?,
+            None => None,
+        };
+        self.state = Some(state.clone());
+        Ok(delta)
+    }
+
+    /// Forget the checkpoint, the next advance will not produce a delta
+    pub fn clear(&mut self) {
+        self.state = None;
+    }
+
+    pub fn state(&self) -> Option<&S> {
+        self.state.as_ref()
+    }
+}
+
+/// Apply a delta returned by `StateCheckpoint::advance` to a copy of the
+/// previous checkpoint
+pub fn apply_delta<S>(state: &mut S, bytes: &[u8]) -> bincode::Result<()>
+where
+    S: Diffable<'static, 'static, S> + Patchable<DeltaOwned<S>>,
+    DeltaOwned<S>: DeserializeOwned,
+{
+    let delta: DeltaOwned<S> = deserialize(bytes)?;
+    state.apply_patch(delta);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ActionRejection, GameState, Outcome, TimeControl};
+
+    /// A client as seen by the room: its local copy and whether the room
+    /// already sent it a full state.
+    struct Replica {
+        state: Option<GameState>,
+        have_full_state: bool,
+    }
+
+    impl Replica {
+        fn new() -> Self {
+            Self {
+                state: None,
+                have_full_state: false,
+            }
+        }
+
+        fn receive(&mut self, server_state: &GameState, delta: &Option<Vec<u8>>) {
+            match delta {
+                Some(bytes) if self.have_full_state => {
+                    apply_delta(self.state.as_mut().unwrap(), bytes).unwrap();
+                }
+                _ => self.state = Some(server_state.clone()),
+            }
+            self.have_full_state = true;
+        }
+    }
+
+    fn assert_same_bytes(a: &GameState, b: &GameState) {
+        assert_eq!(
+            bincode::serialize(a).unwrap(),
+            bincode::serialize(b).unwrap()
+        );
+    }
+
+    #[test]
+    fn first_broadcast_has_no_delta() {
+        let mut checkpoint = StateCheckpoint::new();
+        assert!(checkpoint.advance(&GameState::default()).unwrap().is_none());
+        assert!(checkpoint.advance(&GameState::default()).unwrap().is_some());
+
+        checkpoint.clear();
+        assert!(checkpoint.state().is_none());
+        assert!(checkpoint.advance(&GameState::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn replicas_rebuilt_from_deltas_match_server() {
+        let mut checkpoint = StateCheckpoint::new();
+        let mut server = GameState::default();
+        let mut early = Replica::new();
+        let mut late = Replica::new();
+
+        let moves = [
+            (1, 1),
+            (0, 0),
+            (2, 2),
+            (0, 2),
+            (0, 1),
+            (2, 1),
+            (1, 0),
+            (1, 2),
+            (2, 0),
+        ];
+
+        server.players.push("player_a".to_string());
+        let delta = checkpoint.advance(&server).unwrap();
+        early.receive(&server, &delta);
+
+        server.players.push("player_b".to_string());
+        server.started = true;
+        let delta = checkpoint.advance(&server).unwrap();
+        early.receive(&server, &delta);
+        assert_same_bytes(early.state.as_ref().unwrap(), &server);
+
+        for (i, (x, y)) in moves.iter().enumerate() {
+            let player = server.players[server.turn as usize].clone();
+            server.place_node(*x, *y, &player);
+            server.turn = (server.turn == 0) as u8;
+
+            let delta = checkpoint.advance(&server).unwrap();
+            early.receive(&server, &delta);
+            // the late replica joins half way through the game
+            if i >= moves.len() / 2 {
+                late.receive(&server, &delta);
+                assert_same_bytes(late.state.as_ref().unwrap(), &server);
+            }
+            assert_same_bytes(early.state.as_ref().unwrap(), &server);
+        }
+
+        server.reset_game();
+        let delta = checkpoint.advance(&server).unwrap();
+        early.receive(&server, &delta);
+        late.receive(&server, &delta);
+        assert_same_bytes(early.state.as_ref().unwrap(), &server);
+        assert_same_bytes(late.state.as_ref().unwrap(), &server);
+    }
+
+    #[test]
+    fn full_state_when_no_cell_is_kept() {
+        let mut checkpoint = StateCheckpoint::new();
+        let mut server = GameState::default();
+        server.players = vec!["player_a".to_string(), "player_b".to_string()];
+        checkpoint.advance(&server).unwrap();
+
+        server.place_node(1, 1, &server.players[0].clone());
+        assert!(checkpoint.advance(&server).unwrap().is_some());
+
+        // every row of the board changes at once
+        for x in 0..3 {
+            for y in 0..3 {
+                server.board[x][y] = ((x + y) % 2) as i8;
+            }
+        }
+        assert!(checkpoint.advance(&server).unwrap().is_none());
+        server.reset_game();
+        assert!(checkpoint.advance(&server).unwrap().is_none());
+    }
+
+    #[test]
+    fn boards_of_any_size_sync_through_deltas() {
+        for (width, height, win_length) in [(15, 15, 5), (5, 2, 4), (4, 4, 4)] {
+            let mut checkpoint = StateCheckpoint::new();
+            let mut server = GameState::new(width, height, win_length);
+            let mut replica = Replica::new();
+            server.players = vec!["player_a".to_string(), "player_b".to_string()];
+            server.started = true;
+            replica.receive(&server, &checkpoint.advance(&server).unwrap());
+
+            for (i, (x, y)) in [(0, 0), (1, 3), (1, 1), (0, 3)].iter().enumerate() {
+                server.place_node(*x, *y, &server.players[i % 2].clone());
+                server.turn = (server.turn == 0) as u8;
+                let delta = checkpoint.advance(&server).unwrap();
+                assert!(delta.is_some());
+                replica.receive(&server, &delta);
+            }
+            let state = replica.state.as_ref().unwrap();
+            assert_same_bytes(state, &server);
+            assert_eq!(
+                (state.width(), state.height(), state.win_length()),
+                (width, height, win_length)
+            );
+        }
+    }
+
+    #[test]
+    fn disconnect_and_resume_sync() {
+        let mut checkpoint = StateCheckpoint::new();
+        let mut server = GameState::default();
+        let mut watcher = Replica::new();
+        let (a, b) = ("player_a".to_string(), "player_b".to_string());
+        server.players = vec![a.clone(), b.clone()];
+        server.started = true;
+        watcher.receive(&server, &checkpoint.advance(&server).unwrap());
+        server.place_node(1, 1, &a);
+        server.turn = 1;
+        watcher.receive(&server, &checkpoint.advance(&server).unwrap());
+
+        // b drops, the game waits for it
+        server.disconnected.push(b.clone());
+        let delta = checkpoint.advance(&server).unwrap();
+        assert!(delta.is_some());
+        watcher.receive(&server, &delta);
+        let state = watcher.state.as_ref().unwrap();
+        assert_eq!(state.disconnected, vec![b.clone()]);
+        assert_eq!(
+            state.validate_place_node(0, 0, &b),
+            Err(ActionRejection::WaitingForReconnect)
+        );
+
+        // b resumes on a new connection, which starts from the full state
+        let mut resumed = Replica::new();
+        server.disconnected.retain(|p| *p != b);
+        let delta = checkpoint.advance(&server).unwrap();
+        watcher.receive(&server, &delta);
+        resumed.receive(&server, &delta);
+        for replica in [&watcher, &resumed] {
+            let state = replica.state.as_ref().unwrap();
+            assert_same_bytes(state, &server);
+            assert_eq!(state.validate_place_node(0, 0, &b), Ok(()));
+        }
+    }
+
+    #[test]
+    fn clocks_and_time_out_sync() {
+        let mut checkpoint = StateCheckpoint::new();
+        let mut server = GameState::default();
+        let mut replica = Replica::new();
+        server.players = vec!["player_a".to_string(), "player_b".to_string()];
+        server.started = true;
+        replica.receive(&server, &checkpoint.advance(&server).unwrap());
+
+        let clock = TimeControl::Clock {
+            seconds: 5,
+            increment: 1,
+        };
+        server.start_clocks(&clock);
+        replica.receive(&server, &checkpoint.advance(&server).unwrap());
+        for _ in 0..4 {
+            assert!(!server.run_clock(1_000));
+            replica.receive(&server, &checkpoint.advance(&server).unwrap());
+            assert_same_bytes(replica.state.as_ref().unwrap(), &server);
+        }
+
+        assert!(server.run_clock(1_000));
+        replica.receive(&server, &checkpoint.advance(&server).unwrap());
+        let state = replica.state.as_ref().unwrap();
+        assert_same_bytes(state, &server);
+        assert_eq!(state.time_left, [0, 5_000]);
+        assert_eq!(state.outcome(), Outcome::TimedOut { player: 0 });
+    }
+}
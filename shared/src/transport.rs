@@ -0,0 +1,524 @@
+//! Transports carry whole bincode encoded messages over a byte stream. The
+//! session logic only sees the [`Transport`] trait, so the framing (and the
+//! stream underneath) can change without touching it.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::frame::{encode_frame, FrameDecoder};
+use crate::websocket::{
+    accept_handshake, encode_message, is_close_frame, CloseCode, Message, MessageDecoder,
+    HANDSHAKE_REJECTION,
+};
+
+/// Size of the buffer each read from the stream goes into
+const READ_BUFFER_SIZE: usize = 1024;
+
+/// Why a reader stopped delivering messages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ReadError {
+    /// The peer closed the connection, or it timed out
+    Closed,
+    /// The stream broke the protocol and cannot be read any further
+    Malformed(String),
+}
+
+/// What a reader got from the peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incoming {
+    /// A complete message payload
+    Message(Vec<u8>),
+    /// Bytes the protocol answers with, e.g. the pong to a WebSocket ping.
+    /// They are written with [`TransportWriter::write_reply`] so that they
+    /// never interleave with the messages of the writer.
+    Reply(Vec<u8>),
+}
+
+/// A connection that can be split into halves used from different processes
+pub trait Transport {
+    type Reader: TransportReader;
+    type Writer: TransportWriter;
+
+    fn split(self) -> (Self::Reader, Self::Writer);
+}
+
+pub trait TransportReader {
+    /// Block until the next complete message payload arrives, or until the
+    /// reader has something to answer
+    fn read_message(&mut self) -> Result<Incoming, ReadError>;
+}
+
+pub trait TransportWriter {
+    fn write_message(&mut self, payload: &[u8]) -> bincode::Result<()>;
+
+    /// Write a reply of the reader as it is
+    fn write_reply(&mut self, reply: &[u8]) -> io::Result<()>;
+
+    /// Let the peer know that nothing more will be sent
+    fn close(&mut self) {}
+
+    /// serialize and write the given object as one message.
+    fn send<S: Serialize>(&mut self, s: S) -> bincode::Result<()> {
+        self.write_message(&bincode::serialize(&s)?)
+    }
+}
+
+/// Length-prefixed frames, see [`crate::frame`]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FramedTransport<S>(pub S);
+
+impl<S: Read + Write + Clone> Transport for FramedTransport<S> {
+    type Reader = FramedReader<S>;
+    type Writer = FramedWriter<S>;
+
+    fn split(self) -> (Self::Reader, Self::Writer) {
+        let reader = FramedReader {
+            stream: self.0.clone(),
+            decoder: FrameDecoder::new(),
+        };
+        (reader, FramedWriter(self.0))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FramedReader<S> {
+    stream: S,
+    decoder: FrameDecoder,
+}
+
+impl<S: Read> TransportReader for FramedReader<S> {
+    fn read_message(&mut self) -> Result<Incoming, ReadError> {
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(frame)) => return Ok(Incoming::Message(frame)),
+                Ok(None) => {}
+                // oversized frame, the stream cannot be recovered
+                Err(err) => return Err(ReadError::Malformed(err.to_string())),
+            }
+            match self.stream.read(&mut buffer) {
+                Ok(size) if size > 0 => self.decoder.extend(&buffer[..size]),
+                _ => return Err(ReadError::Closed),
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FramedWriter<S>(S);
+
+impl<S: Write> TransportWriter for FramedWriter<S> {
+    fn write_message(&mut self, payload: &[u8]) -> bincode::Result<()> {
+        self.0.write_all(&encode_frame(payload)?)?;
+        Ok(())
+    }
+
+    fn write_reply(&mut self, reply: &[u8]) -> io::Result<()> {
+        self.0.write_all(reply)
+    }
+}
+
+/// One binary WebSocket message per message, see [`crate::websocket`].
+/// The reader answers the upgrade request, pings and close frames through
+/// the writer.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebSocketTransport<S>(pub S);
+
+impl<S: Read + Write + Clone> Transport for WebSocketTransport<S> {
+    type Reader = WebSocketReader<S>;
+    type Writer = WebSocketWriter<S>;
+
+    fn split(self) -> (Self::Reader, Self::Writer) {
+        let reader = WebSocketReader {
+            stream: self.0.clone(),
+            decoder: MessageDecoder::new(),
+            handshake: Some(Vec::new()),
+            closing: None,
+        };
+        let writer = WebSocketWriter {
+            stream: self.0,
+            closed: false,
+        };
+        (reader, writer)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebSocketReader<S> {
+    stream: S,
+    decoder: MessageDecoder,
+    // upgrade request received so far, until the handshake is done
+    handshake: Option<Vec<u8>>,
+    // returned by every read once the closing reply was handed out
+    closing: Option<ReadError>,
+}
+
+impl<S: Read> WebSocketReader<S> {
+    fn read_more(&mut self) -> Result<(), ReadError> {
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        match self.stream.read(&mut buffer) {
+            Ok(size) if size > 0 => {
+                match &mut self.handshake {
+                    Some(request) => request.extend_from_slice(&buffer[..size]),
+                    None => self.decoder.extend(&buffer[..size]),
+                }
+                Ok(())
+            }
+            _ => Err(ReadError::Closed),
+        }
+    }
+
+    /// The answer to the upgrade request once it is complete, the client may
+    /// send its first frames right after it
+    fn handshake(&mut self) -> Result<Option<Vec<u8>>, ReadError> {
+        while let Some(request) = &self.handshake {
+            match accept_handshake(request) {
+                Ok(Some((response, len))) => {
+                    self.decoder.extend(&request[len..]);
+                    self.handshake = None;
+                    return Ok(Some(response));
+                }
+                Ok(None) => self.read_more()?,
+                Err(_) => {
+                    self.closing = Some(ReadError::Closed);
+                    return Ok(Some(HANDSHAKE_REJECTION.to_vec()));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Answer with a close frame, the next read fails with `error`
+    fn close(&mut self, code: CloseCode, reason: &str, error: ReadError) -> Incoming {
+        self.closing = Some(error);
+        Incoming::Reply(close_frame(code, reason))
+    }
+}
+
+impl<S: Read> TransportReader for WebSocketReader<S> {
+    fn read_message(&mut self) -> Result<Incoming, ReadError> {
+        if let Some(error) = &self.closing {
+            return Err(error.clone());
+        }
+        if let Some(response) = self.handshake()? {
+            return Ok(Incoming::Reply(response));
+        }
+        loop {
+            match self.decoder.next_message() {
+                Ok(Some(Message::Binary(payload))) => return Ok(Incoming::Message(payload)),
+                Ok(Some(Message::Text(_))) => {
+                    return Ok(self.close(
+                        CloseCode::UnsupportedData,
+                        "only binary messages are supported",
+                        ReadError::Closed,
+                    ));
+                }
+                Ok(Some(Message::Ping(payload))) => {
+                    return Ok(Incoming::Reply(encode_message(&Message::Pong(payload))));
+                }
+                Ok(Some(Message::Pong(_))) => {}
+                Ok(Some(Message::Close(_))) => {
+                    return Ok(self.close(CloseCode::Normal, "", ReadError::Closed));
+                }
+                Ok(None) => self.read_more()?,
+                Err(err) => {
+                    let reason = err.to_string();
+                    let error = ReadError::Malformed(reason.clone());
+                    return Ok(self.close(err.close_code(), &reason, error));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebSocketWriter<S> {
+    stream: S,
+    // a close frame was sent, nothing may follow it
+    closed: bool,
+}
+
+impl<S: Write> TransportWriter for WebSocketWriter<S> {
+    fn write_message(&mut self, payload: &[u8]) -> bincode::Result<()> {
+        if self.closed {
+            return Err(Box::new(bincode::ErrorKind::Io(
+                io::ErrorKind::BrokenPipe.into(),
+            )));
+        }
+        self.stream
+            .write_all(&encode_message(&Message::Binary(payload.to_vec())))?;
+        Ok(())
+    }
+
+    fn write_reply(&mut self, reply: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        // the reader answers a close frame, or closes on its own
+        self.closed = is_close_frame(reply);
+        self.stream.write_all(reply)
+    }
+
+    fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            let _ = self.stream.write_all(&close_frame(CloseCode::Normal, ""));
+        }
+    }
+}
+
+/// Close frame with the reason cut short to fit into a control frame
+fn close_frame(code: CloseCode, reason: &str) -> Vec<u8> {
+    let mut len = 2;
+    let reason: String = reason
+        .chars()
+        .take_while(|c| {
+            len += c.len_utf8();
+            len <= 125
+        })
+        .collect();
+    encode_message(&Message::Close(Some((code, reason))))
+}
+
+#[derive(Debug, Default)]
+struct Pipe {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+type SharedPipe = Arc<(Mutex<Pipe>, Condvar)>;
+
+/// One end of an in-memory connection created by [`pipe`]. Clones share the
+/// same end, so it can be used with any of the transports above in tests.
+#[derive(Clone, Debug)]
+pub struct MemoryStream {
+    incoming: SharedPipe,
+    outgoing: SharedPipe,
+}
+
+/// Two connected in-memory streams, bytes written to one end are read from
+/// the other
+pub fn pipe() -> (MemoryStream, MemoryStream) {
+    let (a, b) = (SharedPipe::default(), SharedPipe::default());
+    (
+        MemoryStream {
+            incoming: a.clone(),
+            outgoing: b.clone(),
+        },
+        MemoryStream {
+            incoming: b,
+            outgoing: a,
+        },
+    )
+}
+
+impl MemoryStream {
+    /// Stop writing, the other end reads the end of the stream once it got
+    /// everything written before
+    pub fn shutdown(&self) {
+        let (pipe, written) = &*self.outgoing;
+        pipe.lock().unwrap().closed = true;
+        written.notify_all();
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (pipe, written) = &*self.incoming;
+        let mut pipe = pipe.lock().unwrap();
+        while pipe.bytes.is_empty() && !pipe.closed {
+            pipe = written.wait(pipe).unwrap();
+        }
+        let size = buf.len().min(pipe.bytes.len());
+        for (byte, read) in buf.iter_mut().zip(pipe.bytes.drain(..size)) {
+            *byte = read;
+        }
+        Ok(size)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (pipe, written) = &*self.outgoing;
+        let mut pipe = pipe.lock().unwrap();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        pipe.bytes.extend(buf);
+        written.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::read_frame;
+    use crate::message::{MessageFromClient, MessageFromServer};
+    use crate::serialize::{deserialize, write_serialized};
+
+    const MASK: [u8; 4] = [1, 2, 3, 4];
+
+    /// Masked frame as sent by a WebSocket client, payloads below 126 bytes
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        bytes.extend_from_slice(&MASK);
+        bytes.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        bytes
+    }
+
+    /// Payload of the next message, panics on anything else
+    fn message<R: TransportReader>(reader: &mut R) -> Vec<u8> {
+        match reader.read_message() {
+            Ok(Incoming::Message(payload)) => payload,
+            incoming => panic!("expected a message, got {:?}", incoming),
+        }
+    }
+
+    /// The next reply of the reader, panics on anything else
+    fn reply<R: TransportReader>(reader: &mut R) -> Vec<u8> {
+        match reader.read_message() {
+            Ok(Incoming::Reply(reply)) => reply,
+            incoming => panic!("expected a reply, got {:?}", incoming),
+        }
+    }
+
+    /// Read exactly `len` bytes sent by the server
+    fn read_bytes(stream: &mut MemoryStream, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        stream.read_exact(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn memory_pipe() {
+        let (mut a, mut b) = pipe();
+        a.write_all(b"hello").unwrap();
+        b.write_all(b"bye").unwrap();
+        assert_eq!(read_bytes(&mut b, 5), b"hello");
+        assert_eq!(read_bytes(&mut a, 3), b"bye");
+
+        a.write_all(b"!").unwrap();
+        a.shutdown();
+        assert!(a.write_all(b"?").is_err());
+        let mut rest = Vec::new();
+        b.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"!");
+    }
+
+    #[test]
+    fn framed_transport() {
+        let (server, mut client) = pipe();
+        let (mut reader, mut writer) = FramedTransport(server).split();
+
+        let msg = MessageFromClient::JoinServer("alice".to_string());
+        write_serialized(msg, &mut client).unwrap();
+        // a message split over several writes
+        let bytes = crate::serialize::serialize(MessageFromClient::LeaveServer).unwrap();
+        client.write_all(&bytes[..3]).unwrap();
+        client.write_all(&bytes[3..]).unwrap();
+        let received: MessageFromClient = deserialize(&message(&mut reader)).unwrap();
+        assert!(matches!(received, MessageFromClient::JoinServer(name) if name == "alice"));
+        let received: MessageFromClient = deserialize(&message(&mut reader)).unwrap();
+        assert!(matches!(received, MessageFromClient::LeaveServer));
+
+        writer.send(MessageFromServer::RoomCreated).unwrap();
+        let frame = read_frame(&mut client).unwrap().unwrap();
+        let received: MessageFromServer = deserialize(&frame).unwrap();
+        assert!(matches!(received, MessageFromServer::RoomCreated));
+
+        client.shutdown();
+        assert_eq!(reader.read_message(), Err(ReadError::Closed));
+    }
+
+    #[test]
+    fn framed_transport_rejects_oversized_frames() {
+        let (server, mut client) = pipe();
+        let (mut reader, _) = FramedTransport(server).split();
+        client.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        assert!(matches!(
+            reader.read_message(),
+            Err(ReadError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn websocket_transport() {
+        let (server, mut client) = pipe();
+        let server_end = server.clone();
+        let (mut reader, mut writer) = WebSocketTransport(server).split();
+
+        let request = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        client.write_all(request).unwrap();
+        client.write_all(&client_frame(0x9, b"hi")).unwrap();
+        let payload =
+            bincode::serialize(&MessageFromClient::ListRooms(Default::default())).unwrap();
+        client.write_all(&client_frame(0x2, &payload)).unwrap();
+
+        // the reader leaves the handshake response and the pong to the writer
+        let response = reply(&mut reader);
+        assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        assert_eq!(reply(&mut reader), [0x8A, 2, b'h', b'i']);
+        assert_eq!(message(&mut reader), payload);
+        writer.write_reply(&response).unwrap();
+        writer.write_message(&[1, 2, 3]).unwrap();
+        writer.write_reply(&[0x8A, 2, b'h', b'i']).unwrap();
+        assert_eq!(read_bytes(&mut client, response.len()), response);
+        assert_eq!(read_bytes(&mut client, 5), [0x82, 3, 1, 2, 3]);
+        assert_eq!(read_bytes(&mut client, 4), [0x8A, 2, b'h', b'i']);
+
+        client.write_all(&client_frame(0x8, &[0x03, 0xe8])).unwrap();
+        let close = reply(&mut reader);
+        assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+        assert_eq!(reader.read_message(), Err(ReadError::Closed));
+        writer.write_reply(&close).unwrap();
+        assert_eq!(read_bytes(&mut client, 4), close);
+
+        // nothing follows the close frame
+        assert!(writer.write_message(&[1]).is_err());
+        writer.close();
+        server_end.shutdown();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn websocket_transport_rejects_invalid_handshake() {
+        let (server, mut client) = pipe();
+        let (mut reader, _) = WebSocketTransport(server).split();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(reply(&mut reader), HANDSHAKE_REJECTION);
+        assert_eq!(reader.read_message(), Err(ReadError::Closed));
+    }
+
+    #[test]
+    fn websocket_transport_closes_on_protocol_errors() {
+        let (server, mut client) = pipe();
+        let (mut reader, _) = WebSocketTransport(server).split();
+        let request = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: a2V5\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        client.write_all(request).unwrap();
+        // unmasked frame
+        client.write_all(&[0x82, 1, 0]).unwrap();
+
+        reply(&mut reader);
+        let close = reply(&mut reader);
+        assert_eq!(close[0], 0x88);
+        assert_eq!(
+            close[2..4],
+            u16::from(CloseCode::ProtocolError).to_be_bytes()
+        );
+        assert!(matches!(
+            reader.read_message(),
+            Err(ReadError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,532 @@
+//! Server side of the WebSocket protocol (RFC 6455), so that browsers can
+//! connect without the length-prefixed framing of [`crate::frame`]. Every
+//! binary message carries exactly one bincode encoded message.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::fmt;
+
+/// Appended to the key of the client to prove that the server understood
+/// the handshake
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest upgrade request accepted before the handshake is given up
+pub const MAX_HANDSHAKE_SIZE: usize = 8 * 1024;
+
+/// Largest message accepted, including all of its fragments
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Sent back when the upgrade request is not a valid WebSocket handshake
+pub const HANDSHAKE_REJECTION: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\n\r\n";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Control frames cannot be fragmented and carry at most this many bytes
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Binary(Vec<u8>),
+    Text(String),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(CloseCode, String)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    UnsupportedData,
+    InvalidPayload,
+    MessageTooBig,
+    Other(u16),
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::UnsupportedData => 1003,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::UnsupportedData,
+            1007 => CloseCode::InvalidPayload,
+            1009 => CloseCode::MessageTooBig,
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketError {
+    Handshake(&'static str),
+    Protocol(&'static str),
+    InvalidPayload,
+    MessageTooBig,
+}
+
+impl WebSocketError {
+    /// Code of the close frame sent before dropping the connection
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            WebSocketError::Handshake(_) | WebSocketError::Protocol(_) => CloseCode::ProtocolError,
+            WebSocketError::InvalidPayload => CloseCode::InvalidPayload,
+            WebSocketError::MessageTooBig => CloseCode::MessageTooBig,
+        }
+    }
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketError::Handshake(reason) => write!(f, "invalid handshake: {}", reason),
+            WebSocketError::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            WebSocketError::InvalidPayload => write!(f, "text message is not valid UTF-8"),
+            WebSocketError::MessageTooBig => write!(f, "message too big"),
+        }
+    }
+}
+
+impl std::error::Error for WebSocketError {}
+
+/// Value of the `Sec-WebSocket-Accept` header for the given key
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+/// Check the HTTP upgrade request at the start of `buffer`. Returns the
+/// response to send back and the number of bytes the request took, or `None`
+/// while the request is incomplete.
+pub fn accept_handshake(buffer: &[u8]) -> Result<Option<(Vec<u8>, usize)>, WebSocketError> {
+    let end = match buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None if buffer.len() > MAX_HANDSHAKE_SIZE => {
+            return Err(WebSocketError::Handshake("request too big"))
+        }
+        None => return Ok(None),
+    };
+    let request = std::str::from_utf8(&buffer[..end])
+        .map_err(|_| WebSocketError::Handshake("request is not valid UTF-8"))?;
+    let mut lines = request.split("\r\n");
+
+    let request_line: Vec<_> = lines.next().unwrap_or_default().split(' ').collect();
+    if !matches!(request_line[..], ["GET", _, "HTTP/1.1"]) {
+        return Err(WebSocketError::Handshake("expected a HTTP/1.1 GET request"));
+    }
+    let headers: Vec<_> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| *value)
+    };
+
+    if !header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket")) {
+        return Err(WebSocketError::Handshake("missing upgrade header"));
+    }
+    let upgrade_connection = header("connection").is_some_and(|value| {
+        value
+            .split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    if !upgrade_connection {
+        return Err(WebSocketError::Handshake("missing connection upgrade"));
+    }
+    if header("sec-websocket-version") != Some("13") {
+        return Err(WebSocketError::Handshake("unsupported version"));
+    }
+    let key = header("sec-websocket-key")
+        .filter(|key| !key.is_empty())
+        .ok_or(WebSocketError::Handshake("missing key"))?;
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    Ok(Some((response.into_bytes(), end + 4)))
+}
+
+/// True if the frame encoded by `encode_message` is a close frame
+pub fn is_close_frame(frame: &[u8]) -> bool {
+    frame.first() == Some(&(0x80 | OPCODE_CLOSE))
+}
+
+/// Encode a message as a single unmasked frame, as sent by servers
+pub fn encode_message(message: &Message) -> Vec<u8> {
+    let (opcode, payload) = match message {
+        Message::Binary(bytes) => (OPCODE_BINARY, bytes.clone()),
+        Message::Text(text) => (OPCODE_TEXT, text.as_bytes().to_vec()),
+        Message::Ping(bytes) => (OPCODE_PING, bytes.clone()),
+        Message::Pong(bytes) => (OPCODE_PONG, bytes.clone()),
+        Message::Close(None) => (OPCODE_CLOSE, Vec::new()),
+        Message::Close(Some((code, reason))) => {
+            let mut payload = u16::from(*code).to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.as_bytes());
+            (OPCODE_CLOSE, payload)
+        }
+    };
+
+    let mut bytes = Vec::with_capacity(payload.len() + 10);
+    bytes.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => bytes.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            bytes.push(126);
+            bytes.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            bytes.push(127);
+            bytes.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// Streaming decoder for the frames sent by a client. Fragmented messages
+/// are put back together, control frames in between them are returned as
+/// soon as they arrive.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MessageDecoder {
+    buffer: Vec<u8>,
+    // opcode and payload of a fragmented message received so far
+    fragments: Option<(u8, Vec<u8>)>,
+    max_message_size: usize,
+}
+
+impl Default for MessageDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageDecoder {
+    pub fn new() -> Self {
+        Self::with_max_message_size(MAX_MESSAGE_SIZE)
+    }
+
+    pub fn with_max_message_size(max_message_size: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            fragments: None,
+            max_message_size,
+        }
+    }
+
+    /// Append freshly read bytes to the internal buffer.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Pop the next complete message, or `None` if more bytes are needed.
+    /// Errors leave the decoder unusable, the connection should be closed
+    /// with `WebSocketError::close_code`.
+    pub fn next_message(&mut self) -> Result<Option<Message>, WebSocketError> {
+        while let Some((fin, opcode, payload)) = self.next_frame()? {
+            let (opcode, payload) = match opcode {
+                OPCODE_PING | OPCODE_PONG | OPCODE_CLOSE => {
+                    return control_message(opcode, payload).map(Some)
+                }
+                OPCODE_CONTINUATION => {
+                    let (opcode, mut message) = self
+                        .fragments
+                        .take()
+                        .ok_or(WebSocketError::Protocol("unexpected continuation frame"))?;
+                    message.extend_from_slice(&payload);
+                    (opcode, message)
+                }
+                OPCODE_TEXT | OPCODE_BINARY if self.fragments.is_some() => {
+                    return Err(WebSocketError::Protocol("expected a continuation frame"))
+                }
+                OPCODE_TEXT | OPCODE_BINARY => (opcode, payload),
+                _ => return Err(WebSocketError::Protocol("unknown opcode")),
+            };
+            if payload.len() > self.max_message_size {
+                return Err(WebSocketError::MessageTooBig);
+            }
+            if !fin {
+                self.fragments = Some((opcode, payload));
+                continue;
+            }
+            return Ok(Some(match opcode {
+                OPCODE_TEXT => Message::Text(
+                    String::from_utf8(payload).map_err(|_| WebSocketError::InvalidPayload)?,
+                ),
+                _ => Message::Binary(payload),
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Pop the next complete frame as its fin bit, opcode and unmasked payload
+    fn next_frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, WebSocketError> {
+        if self.buffer.len() < 2 {
+            return Ok(None);
+        }
+        let (first, second) = (self.buffer[0], self.buffer[1]);
+        let fin = first & 0x80 != 0;
+        let opcode = first & 0x0F;
+        if first & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits are set"));
+        }
+        if second & 0x80 == 0 {
+            return Err(WebSocketError::Protocol("client frames must be masked"));
+        }
+        let is_control = opcode & 0x8 != 0;
+
+        let (len, mut offset) = match second & 0x7F {
+            126 if self.buffer.len() < 4 => return Ok(None),
+            126 => (
+                u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as u64,
+                4,
+            ),
+            127 if self.buffer.len() < 10 => return Ok(None),
+            127 => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&self.buffer[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            len => (len as u64, 2),
+        };
+        if is_control && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(WebSocketError::Protocol("invalid control frame"));
+        }
+        // checked before the cast, `usize` may only have 32 bits
+        if len > self.max_message_size as u64 {
+            return Err(WebSocketError::MessageTooBig);
+        }
+        let len = len as usize;
+        if self.buffer.len() < offset + 4 + len {
+            return Ok(None);
+        }
+
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&self.buffer[offset..offset + 4]);
+        offset += 4;
+        let payload = self.buffer[offset..offset + len]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        self.buffer.drain(..offset + len);
+        Ok(Some((fin, opcode, payload)))
+    }
+}
+
+fn control_message(opcode: u8, payload: Vec<u8>) -> Result<Message, WebSocketError> {
+    Ok(match opcode {
+        OPCODE_PING => Message::Ping(payload),
+        OPCODE_PONG => Message::Pong(payload),
+        _ => match payload.len() {
+            0 => Message::Close(None),
+            1 => return Err(WebSocketError::Protocol("close frame without a full code")),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                let reason = String::from_utf8(payload[2..].to_vec())
+                    .map_err(|_| WebSocketError::InvalidPayload)?;
+                Message::Close(Some((code.into(), reason)))
+            }
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// Masked frame as sent by a client
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![(fin as u8) << 7 | opcode];
+        match payload.len() {
+            len if len < 126 => bytes.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                bytes.push(0x80 | 126);
+                bytes.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                bytes.push(0x80 | 127);
+                bytes.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        bytes.extend_from_slice(&MASK);
+        bytes.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Option<Message>, WebSocketError> {
+        let mut decoder = MessageDecoder::new();
+        decoder.extend(bytes);
+        decoder.next_message()
+    }
+
+    #[test]
+    fn accept_key_from_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn handshake() {
+        let request = b"GET /chat HTTP/1.1\r\n\
+            Host: server.example.com\r\n\
+            Upgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(accept_handshake(&request[..40]), Ok(None));
+
+        let mut buffer = request.to_vec();
+        buffer.extend_from_slice(&client_frame(true, OPCODE_BINARY, b"hi"));
+        let (response, len) = accept_handshake(&buffer).unwrap().unwrap();
+        assert_eq!(len, request.len());
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        let without_key = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        assert!(accept_handshake(without_key).is_err());
+        let old_version = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: a2V5\r\nSec-WebSocket-Version: 8\r\n\r\n";
+        assert!(accept_handshake(old_version).is_err());
+        assert!(accept_handshake(&[b'a'; MAX_HANDSHAKE_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn encode_server_frames() {
+        assert_eq!(
+            encode_message(&Message::Binary(vec![1, 2, 3])),
+            vec![0x82, 3, 1, 2, 3]
+        );
+        let bytes = encode_message(&Message::Binary(vec![0; 126]));
+        assert_eq!(bytes[..4], [0x82, 126, 0, 126]);
+        let bytes = encode_message(&Message::Binary(vec![0; 70_000]));
+        assert_eq!(bytes[..2], [0x82, 127]);
+        assert_eq!(bytes[2..10], 70_000u64.to_be_bytes());
+        assert_eq!(
+            encode_message(&Message::Close(Some((
+                CloseCode::Normal,
+                "bye".to_string()
+            )))),
+            vec![0x88, 5, 0x03, 0xe8, b'b', b'y', b'e']
+        );
+    }
+
+    #[test]
+    fn decode_client_frames() {
+        let bytes = client_frame(true, OPCODE_BINARY, &[1, 2, 3]);
+        assert_eq!(decode(&bytes), Ok(Some(Message::Binary(vec![1, 2, 3]))));
+
+        // frames can arrive in several reads
+        let mut decoder = MessageDecoder::new();
+        let payload = vec![7; 300];
+        let bytes = client_frame(true, OPCODE_BINARY, &payload);
+        for chunk in bytes.chunks(7) {
+            assert_eq!(decoder.next_message(), Ok(None));
+            decoder.extend(chunk);
+        }
+        assert_eq!(decoder.next_message(), Ok(Some(Message::Binary(payload))));
+        assert_eq!(decoder.next_message(), Ok(None));
+
+        let bytes = client_frame(true, OPCODE_TEXT, "héllo".as_bytes());
+        assert_eq!(decode(&bytes), Ok(Some(Message::Text("héllo".to_string()))));
+        let bytes = client_frame(true, OPCODE_PING, b"ping");
+        assert_eq!(decode(&bytes), Ok(Some(Message::Ping(b"ping".to_vec()))));
+        let bytes = client_frame(true, OPCODE_CLOSE, &[0x03, 0xe9]);
+        assert_eq!(
+            decode(&bytes),
+            Ok(Some(Message::Close(Some((
+                CloseCode::GoingAway,
+                String::new()
+            )))))
+        );
+    }
+
+    #[test]
+    fn fragmented_message_with_ping_in_between() {
+        let mut decoder = MessageDecoder::new();
+        decoder.extend(&client_frame(false, OPCODE_BINARY, &[1, 2]));
+        decoder.extend(&client_frame(true, OPCODE_PING, &[]));
+        decoder.extend(&client_frame(false, OPCODE_CONTINUATION, &[3]));
+        decoder.extend(&client_frame(true, OPCODE_CONTINUATION, &[4]));
+        assert_eq!(decoder.next_message(), Ok(Some(Message::Ping(Vec::new()))));
+        assert_eq!(
+            decoder.next_message(),
+            Ok(Some(Message::Binary(vec![1, 2, 3, 4])))
+        );
+        assert_eq!(decoder.next_message(), Ok(None));
+    }
+
+    #[test]
+    fn reject_invalid_frames() {
+        let mut unmasked = client_frame(true, OPCODE_BINARY, &[1]);
+        unmasked[1] &= 0x7F;
+        assert_eq!(
+            decode(&unmasked).unwrap_err().close_code(),
+            CloseCode::ProtocolError
+        );
+
+        let continuation = client_frame(true, OPCODE_CONTINUATION, &[1]);
+        assert!(decode(&continuation).is_err());
+
+        let fragmented_ping = client_frame(false, OPCODE_PING, &[]);
+        assert!(decode(&fragmented_ping).is_err());
+
+        let invalid_text = client_frame(true, OPCODE_TEXT, &[0xff, 0xfe]);
+        assert_eq!(
+            decode(&invalid_text).unwrap_err().close_code(),
+            CloseCode::InvalidPayload
+        );
+
+        let mut decoder = MessageDecoder::with_max_message_size(4);
+        decoder.extend(&client_frame(false, OPCODE_BINARY, &[1, 2, 3]));
+        decoder.extend(&client_frame(true, OPCODE_CONTINUATION, &[4, 5]));
+        assert_eq!(decoder.next_message(), Err(WebSocketError::MessageTooBig));
+
+        // a length that only fits into 32 bits once truncated
+        let mut huge = vec![0x80 | OPCODE_BINARY, 0x80 | 127];
+        huge.extend_from_slice(&((1u64 << 32) + 1).to_be_bytes());
+        huge.extend_from_slice(&[0; 5]);
+        assert_eq!(decode(&huge), Err(WebSocketError::MessageTooBig));
+        assert_eq!(
+            WebSocketError::MessageTooBig.close_code(),
+            CloseCode::MessageTooBig
+        );
+    }
+}